@@ -1,151 +1,1559 @@
 use axum::{
-    body::Bytes, extract::Json, http::{header::SET_COOKIE, HeaderMap, StatusCode}, response::IntoResponse
+    body::Bytes, extract::{Json, Query, State}, http::{header::{COOKIE, SET_COOKIE}, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}
 };
-use jsonwebtoken::{encode, decode, Header, EncodingKey, DecodingKey, Validation, Algorithm};
+use jsonwebtoken::{encode, decode, decode_header, Header, EncodingKey, DecodingKey, Validation, Algorithm};
 use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk, JwkSet,
+    KeyAlgorithm, OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters,
+};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey, pkcs8::{DecodePrivateKey, SubjectPublicKeyInfoRef}, traits::PublicKeyParts,
+    Oaep, RsaPrivateKey, RsaPublicKey,
+};
+use sha1::Sha1;
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
-use chrono::{Duration, Utc};
-use std::collections::HashSet;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     data: Value,
     exp: usize,
+    jti: String,
+    #[serde(flatten)]
+    custom: GiftClaims,
+}
+
+// wrap で呼び出し側が指定できるクレーム
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct GiftClaims {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nbf: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    // プライベートクレーム
+    #[serde(flatten)]
+    private: serde_json::Map<String, Value>,
+}
+
+// サーバーが決めるクレームはプライベートクレームとして上書きさせない
+const RESERVED_CLAIMS: [&str; 8] = ["data", "exp", "jti", "iat", "sub", "aud", "nbf", "iss"];
+
+impl GiftClaims {
+    fn from_query(query: &WrapQuery) -> Result<Self, String> {
+        let private = match &query.claims {
+            Some(claims) => match serde_json::from_str::<Value>(claims) {
+                Ok(Value::Object(claims)) => claims,
+                _ => return Err("claims must be a JSON object".to_string()),
+            },
+            None => serde_json::Map::new(),
+        };
+        if let Some(name) = private.keys().find(|name| RESERVED_CLAIMS.contains(&name.as_str())) {
+            return Err(format!("{} cannot be set as a private claim", name));
+        }
+        Ok(Self {
+            sub: query.sub.clone(),
+            aud: query.aud.as_ref().map(|aud| aud.split(',').map(|aud| aud.trim().to_string()).collect()),
+            nbf: query.nbf,
+            iss: query.iss.clone(),
+            private,
+        })
+    }
+
+    // 既存のgiftのクレームから引き継ぐ
+    fn from_claims(claims: &Value) -> Self {
+        let mut claims = claims.as_object().cloned().unwrap_or_default();
+        for name in ["data", "exp", "jti"] {
+            claims.remove(name);
+        }
+        if let Some(Value::String(aud)) = claims.get("aud").cloned() {
+            claims.insert("aud".to_string(), Value::Array(vec![Value::String(aud)]));
+        }
+        serde_json::from_value(Value::Object(claims)).unwrap_or_default()
+    }
+}
+
+const DEFAULT_JWT_SECRET: &str = "santa_secret_key";
+const DEFAULT_TOKEN_TTL: i64 = 60 * 60 * 24;
+const DEFAULT_REVOCATION_CACHE_TTL: i64 = 30;
+
+const SUPPORTED_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::HS256,
+    Algorithm::HS384,
+    Algorithm::HS512,
+    Algorithm::RS256,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+// PEMは値そのものか、ファイルパスで渡せる
+fn load_pem(value: &str) -> Result<Vec<u8>, String> {
+    if value.trim_start().starts_with("-----BEGIN") {
+        Ok(value.as_bytes().to_vec())
+    } else {
+        std::fs::read(value).map_err(|e| format!("Failed to read {}: {}", value, e))
+    }
+}
+
+fn pem_to_der(pem: &[u8]) -> Result<Vec<u8>, String> {
+    let pem = std::str::from_utf8(pem).map_err(|_| "PEM is not UTF-8".to_string())?;
+    let body: String = pem.lines().filter(|line| !line.starts_with("-----")).map(str::trim).collect();
+    STANDARD.decode(body).map_err(|e| format!("Invalid PEM: {}", e))
+}
+
+// 公開鍵のPEMからJWKS用のJWKを作る
+fn public_jwk(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Result<Jwk, String> {
+    let der = pem_to_der(pem)?;
+    let spki = SubjectPublicKeyInfoRef::try_from(der.as_slice()).map_err(|e| format!("Invalid public key: {}", e))?;
+
+    let parameters = match algorithm {
+        Algorithm::RS256 => {
+            let key = RsaPublicKey::try_from(spki).map_err(|e| format!("Invalid RSA public key: {}", e))?;
+            AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: Default::default(),
+                n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            })
+        },
+        Algorithm::ES256 => {
+            // 非圧縮形式 0x04 || x || y
+            let point = spki.subject_public_key.raw_bytes();
+            if point.len() != 65 || point[0] != 0x04 {
+                return Err("Invalid P-256 public key".to_string());
+            }
+            AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: Default::default(),
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+                y: URL_SAFE_NO_PAD.encode(&point[33..]),
+            })
+        },
+        Algorithm::EdDSA => AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: Default::default(),
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(spki.subject_public_key.raw_bytes()),
+        }),
+        _ => return Err(format!("{:?} has no public key", algorithm)),
+    };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: KeyAlgorithm::from_str(&format!("{:?}", algorithm)).ok(),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    })
+}
+
+// キーリングの1エントリ
+//
+// [[keys]]
+// kid = "2025-01"
+// algorithm = "RS256"
+// private_key = "keys/2025-01.pem"
+// public_key = "keys/2025-01.pub.pem"
+// retired_at = "2025-02-01T00:00:00Z"
+#[derive(Debug, Deserialize)]
+struct KeyConfig {
+    kid: String,
+    algorithm: String,
+    secret: Option<String>,
+    private_key: Option<String>,
+    public_key: Option<String>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyringConfig {
+    active: String,
+    keys: Vec<KeyConfig>,
+}
+
+impl KeyringConfig {
+    // GIFT_KEYRING が無ければ単一の鍵を環境変数から組み立てる
+    //
+    // GIFT_JWT_KID         鍵ID (既定: default)
+    // GIFT_JWT_ALGORITHM   HS256 / HS384 / HS512 / RS256 / ES256 / EdDSA (既定: HS256)
+    // GIFT_JWT_SECRET      HS* の共有鍵
+    // GIFT_JWT_PRIVATE_KEY RS256 / ES256 / EdDSA の秘密鍵 (PEM)
+    // GIFT_JWT_PUBLIC_KEY  RS256 / ES256 / EdDSA の公開鍵 (PEM)
+    fn from_env() -> Result<Self, String> {
+        if let Ok(path) = std::env::var("GIFT_KEYRING") {
+            let config = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            return toml::from_str(&config).map_err(|e| format!("Invalid keyring: {}", e));
+        }
+
+        let kid = std::env::var("GIFT_JWT_KID").unwrap_or("default".to_string());
+        Ok(Self {
+            active: kid.clone(),
+            keys: vec![KeyConfig {
+                kid,
+                algorithm: std::env::var("GIFT_JWT_ALGORITHM").unwrap_or("HS256".to_string()),
+                secret: Some(std::env::var("GIFT_JWT_SECRET").unwrap_or(DEFAULT_JWT_SECRET.to_string())),
+                private_key: std::env::var("GIFT_JWT_PRIVATE_KEY").ok(),
+                public_key: std::env::var("GIFT_JWT_PUBLIC_KEY").ok(),
+                retired_at: None,
+            }],
+        })
+    }
+}
+
+struct GiftKey {
+    kid: String,
+    algorithm: Algorithm,
+    // 引退済みの鍵は秘密鍵を持たなくてもよい
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    jwk: Option<Jwk>,
+    retired_at: Option<DateTime<Utc>>,
+}
+
+impl GiftKey {
+    fn from_config(config: KeyConfig) -> Result<Self, String> {
+        let algorithm = Algorithm::from_str(&config.algorithm).map_err(|_| format!("Unknown algorithm {}", config.algorithm))?;
+        if !SUPPORTED_ALGORITHMS.contains(&algorithm) {
+            return Err(format!("Unsupported algorithm {:?}", algorithm));
+        }
+        let invalid = |e: jsonwebtoken::errors::Error| format!("Invalid key {}: {}", config.kid, e);
+
+        let (encoding, decoding, jwk) = match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = config.secret.as_ref().ok_or(format!("{} has no secret", config.kid))?;
+                (Some(EncodingKey::from_secret(secret.as_bytes())), DecodingKey::from_secret(secret.as_bytes()), None)
+            },
+            _ => {
+                let public_key = load_pem(config.public_key.as_ref().ok_or(format!("{} has no public key", config.kid))?)?;
+                let private_key = config.private_key.as_deref().map(load_pem).transpose()?;
+                let (encoding, decoding) = match algorithm {
+                    Algorithm::RS256 => (private_key.map(|k| EncodingKey::from_rsa_pem(&k)).transpose(), DecodingKey::from_rsa_pem(&public_key)),
+                    Algorithm::ES256 => (private_key.map(|k| EncodingKey::from_ec_pem(&k)).transpose(), DecodingKey::from_ec_pem(&public_key)),
+                    _ => (private_key.map(|k| EncodingKey::from_ed_pem(&k)).transpose(), DecodingKey::from_ed_pem(&public_key)),
+                };
+                (encoding.map_err(invalid)?, decoding.map_err(invalid)?, Some(public_jwk(&config.kid, algorithm, &public_key)?))
+            },
+        };
+
+        Ok(Self { kid: config.kid, algorithm, encoding, decoding, jwk, retired_at: config.retired_at })
+    }
+
+    // 引退後も、その時点で発行済みのトークンが切れるまでは検証に使う
+    fn is_verifiable(&self) -> bool {
+        match self.retired_at {
+            Some(retired_at) => Utc::now() < retired_at + Duration::seconds(max_token_ttl()),
+            None => true,
+        }
+    }
+}
+
+struct GiftKeyring {
+    active: usize,
+    keys: Vec<GiftKey>,
+}
+
+impl GiftKeyring {
+    fn from_env() -> Result<Self, String> {
+        let config = KeyringConfig::from_env()?;
+        let keys = config.keys.into_iter().map(GiftKey::from_config).collect::<Result<Vec<_>, _>>()?;
+        let active = keys.iter().position(|key| key.kid == config.active)
+            .ok_or(format!("Active key {} is not in the keyring", config.active))?;
+        if keys[active].encoding.is_none() || keys[active].retired_at.is_some() {
+            return Err(format!("Active key {} cannot sign", config.active));
+        }
+        Ok(Self { active, keys })
+    }
+
+    fn active(&self) -> &GiftKey {
+        &self.keys[self.active]
+    }
+
+    // kid の無い古いトークンはアクティブな鍵で検証する
+    fn find(&self, kid: Option<&str>) -> Option<&GiftKey> {
+        match kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid == kid && key.is_verifiable()),
+            None => Some(self.active()),
+        }
+    }
+
+    fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter()
+                .filter(|key| key.is_verifiable())
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+static GIFT_KEYRING: LazyLock<Result<GiftKeyring, String>> = LazyLock::new(GiftKeyring::from_env);
+
+fn gift_keyring() -> &'static GiftKeyring {
+    GIFT_KEYRING.as_ref().expect("gift keyring is checked by init")
+}
+
+// 設定の誤りはリクエスト時ではなく起動時に報告する
+pub fn init() -> Result<(), String> {
+    GIFT_KEYRING.as_ref().map_err(Clone::clone)?;
+    GIFT_ENCRYPTION.as_ref().map_err(Clone::clone)?;
+    SANTA_KEY.as_ref().map_err(Clone::clone)?;
+    TRUSTED_ISSUERS.as_ref().map_err(Clone::clone)?;
+    MAX_TOKEN_TTL.as_ref().map_err(Clone::clone)?;
+    REVOCATION_CACHE_TTL.as_ref().map_err(Clone::clone)?;
+    GIFT_COOKIE.as_ref().map_err(Clone::clone)?;
+    Ok(())
+}
+
+// 未設定なら default を使い、設定されていれば min 秒以上でなければならない
+fn env_seconds(var: &str, default: i64, min: i64) -> Result<i64, String> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(default);
+    };
+    value.trim()
+        .parse()
+        .ok()
+        .filter(|seconds| *seconds >= min)
+        .ok_or_else(|| format!("{} must be an integer of at least {} seconds, got {}", var, min, value))
+}
+
+// GIFT_TOKEN_MAX_TTL 秒を超える有効期限は切り詰める
+static MAX_TOKEN_TTL: LazyLock<Result<i64, String>> = LazyLock::new(|| env_seconds("GIFT_TOKEN_MAX_TTL", DEFAULT_TOKEN_TTL, 1));
+
+fn max_token_ttl() -> i64 {
+    *MAX_TOKEN_TTL.as_ref().expect("GIFT_TOKEN_MAX_TTL is checked by init")
+}
+
+// 失効していないと確認した jti を GIFT_REVOCATION_CACHE_TTL 秒はDBに問い合わせない
+// (他のインスタンスでの失効はこの時間だけ遅れて反映される。0 ならキャッシュしない)
+static REVOCATION_CACHE_TTL: LazyLock<Result<i64, String>> = LazyLock::new(|| {
+    env_seconds("GIFT_REVOCATION_CACHE_TTL", DEFAULT_REVOCATION_CACHE_TTL, 0)
+});
+
+fn revocation_cache_ttl() -> i64 {
+    *REVOCATION_CACHE_TTL.as_ref().expect("GIFT_REVOCATION_CACHE_TTL is checked by init")
+}
+
+pub const MAKE_DB_SQL: &str = "CREATE TABLE IF NOT EXISTS gift_revocations (
+    jti TEXT PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);";
+
+const INSERT_REVOCATION_SQL: &str = "INSERT INTO gift_revocations (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO NOTHING;";
+
+const SELECT_REVOCATION_SQL: &str = "SELECT expires_at FROM gift_revocations WHERE jti = $1;";
+
+// 期限切れのトークンは失効させなくても通らないので消してよい
+const DELETE_EXPIRED_REVOCATIONS_SQL: &str = "DELETE FROM gift_revocations WHERE expires_at < CURRENT_TIMESTAMP;";
+
+#[derive(Clone)]
+pub struct StateGifts {
+    pub pool: Arc<sqlx::PgPool>,
+    // 失効済みと分かっている jti と、そのトークンの有効期限
+    pub revoked: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    // 失効していないと確認した jti と、確認した時刻
+    pub unrevoked: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl StateGifts {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        Self {
+            pool: Arc::new(pool),
+            revoked: Arc::new(Mutex::new(HashMap::new())),
+            unrevoked: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // キャッシュに無ければDBを見る(他のインスタンスで失効した分)
+    async fn is_revoked(&self, jti: &str) -> Result<bool, sqlx::Error> {
+        if self.revoked.lock().unwrap().contains_key(jti) {
+            return Ok(true);
+        }
+        let now = Utc::now();
+        let ttl = Duration::seconds(revocation_cache_ttl());
+        if self.unrevoked.lock().unwrap().get(jti).is_some_and(|checked_at| now - *checked_at < ttl) {
+            return Ok(false);
+        }
+
+        let expires_at: Option<DateTime<Utc>> = sqlx::query_scalar(SELECT_REVOCATION_SQL)
+            .bind(jti)
+            .fetch_optional(&*self.pool)
+            .await?;
+        match expires_at {
+            Some(expires_at) => {
+                self.revoked.lock().unwrap().insert(jti.to_string(), expires_at);
+                Ok(true)
+            },
+            None => {
+                let mut unrevoked = self.unrevoked.lock().unwrap();
+                unrevoked.retain(|_, checked_at| now - *checked_at < ttl);
+                unrevoked.insert(jti.to_string(), now);
+                Ok(false)
+            },
+        }
+    }
+
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query(DELETE_EXPIRED_REVOCATIONS_SQL)
+            .execute(&*self.pool)
+            .await?;
+        sqlx::query(INSERT_REVOCATION_SQL)
+            .bind(jti)
+            .bind(expires_at)
+            .execute(&*self.pool)
+            .await?;
+
+        let now = Utc::now();
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, expires_at| *expires_at > now);
+        revoked.insert(jti.to_string(), expires_at);
+        self.unrevoked.lock().unwrap().remove(jti);
+        Ok(())
+    }
 }
 
-const JWT_SECRET: &[u8] = b"santa_secret_key";
+const JWE_DIRECT: &str = "dir";
+const JWE_RSA_OAEP: &str = "RSA-OAEP";
+const JWE_A256GCM: &str = "A256GCM";
 
-pub async fn wrap(Json(body): Json<Value>) -> impl IntoResponse {
-    let exp = (Utc::now() + Duration::days(1)).timestamp() as usize;
+#[derive(Debug, Serialize, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+}
+
+enum ContentKey {
+    Direct([u8; 32]),
+    RsaOaep(Box<RsaPrivateKey>),
+}
+
+// 署名済みのトークンをさらにJWE(コンパクト形式)で包む
+//
+// GIFT_JWE_ALGORITHM   dir / RSA-OAEP (既定: dir)
+// GIFT_JWE_KEY         dir の256bit鍵 (base64)
+// GIFT_JWE_PRIVATE_KEY RSA-OAEP の秘密鍵 (PEM)
+//
+// 再起動や別インスタンスでも復号できるよう、鍵は必ず明示的に渡す
+struct GiftEncryption {
+    key: ContentKey,
+}
+
+impl GiftEncryption {
+    // 鍵が設定されていなければ暗号化は使えない(GIFT_ENCRYPT が有効ならエラー)
+    fn from_env() -> Result<Option<Self>, String> {
+        let algorithm = std::env::var("GIFT_JWE_ALGORITHM").ok();
+        if algorithm.is_none() && std::env::var("GIFT_JWE_KEY").is_err() {
+            if env_flag("GIFT_ENCRYPT") {
+                return Err("GIFT_ENCRYPT requires GIFT_JWE_KEY or GIFT_JWE_ALGORITHM".to_string());
+            }
+            return Ok(None);
+        }
+
+        let algorithm = algorithm.unwrap_or(JWE_DIRECT.to_string());
+        let key = match algorithm.as_str() {
+            JWE_DIRECT => {
+                let encoded = std::env::var("GIFT_JWE_KEY").map_err(|_| "GIFT_JWE_KEY is not set".to_string())?;
+                let decoded = STANDARD.decode(encoded.trim()).map_err(|e| format!("Invalid GIFT_JWE_KEY: {}", e))?;
+                ContentKey::Direct(decoded.try_into().map_err(|_| "GIFT_JWE_KEY must be 32 bytes".to_string())?)
+            },
+            JWE_RSA_OAEP => {
+                let pem = std::env::var("GIFT_JWE_PRIVATE_KEY").map_err(|_| "GIFT_JWE_PRIVATE_KEY is not set".to_string())?;
+                let pem = String::from_utf8(load_pem(&pem)?).map_err(|_| "PEM is not UTF-8".to_string())?;
+                let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+                    .map_err(|e| format!("Invalid GIFT_JWE_PRIVATE_KEY: {}", e))?;
+                ContentKey::RsaOaep(Box::new(key))
+            },
+            _ => return Err(format!("Unsupported JWE algorithm {}", algorithm)),
+        };
+        Ok(Some(Self { key }))
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self.key {
+            ContentKey::Direct(_) => JWE_DIRECT,
+            ContentKey::RsaOaep(_) => JWE_RSA_OAEP,
+        }
+    }
+
+    fn encrypt(&self, payload: &str) -> Result<String, String> {
+        let header = JweHeader {
+            alg: self.algorithm().to_string(),
+            enc: JWE_A256GCM.to_string(),
+            cty: Some("JWT".to_string()),
+        };
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+
+        let (cek, encrypted_key) = match &self.key {
+            ContentKey::Direct(key) => (*key, Vec::new()),
+            ContentKey::RsaOaep(key) => {
+                let mut cek = [0u8; 32];
+                rand::thread_rng().fill(&mut cek);
+                let encrypted_key = RsaPublicKey::from(key.as_ref())
+                    .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha1>(), &cek)
+                    .map_err(|e| format!("Failed to encrypt content key: {}", e))?;
+                (cek, encrypted_key)
+            },
+        };
+
+        let mut iv = [0u8; 12];
+        rand::thread_rng().fill(&mut iv);
+        let mut ciphertext = payload.as_bytes().to_vec();
+        let tag = aes_gcm_key(&cek)?
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(iv), Aad::from(header.as_bytes()), &mut ciphertext)
+            .map_err(|_| "Failed to encrypt gift".to_string())?;
+
+        Ok([
+            header,
+            URL_SAFE_NO_PAD.encode(encrypted_key),
+            URL_SAFE_NO_PAD.encode(iv),
+            URL_SAFE_NO_PAD.encode(ciphertext),
+            URL_SAFE_NO_PAD.encode(tag.as_ref()),
+        ].join("."))
+    }
+
+    fn decrypt(&self, token: &str) -> Result<String, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header_b64, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            return Err("JWE must have 5 parts".to_string());
+        };
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|e| format!("Invalid base64: {}", e));
+
+        let header: JweHeader = serde_json::from_slice(&decode(header_b64)?).map_err(|e| format!("Invalid JWE header: {}", e))?;
+        if header.alg != self.algorithm() || header.enc != JWE_A256GCM {
+            return Err(format!("Unsupported JWE algorithm {}+{}", header.alg, header.enc));
+        }
+
+        let cek: [u8; 32] = match &self.key {
+            ContentKey::Direct(key) => *key,
+            ContentKey::RsaOaep(key) => key
+                .decrypt(Oaep::new::<Sha1>(), &decode(encrypted_key)?)
+                .map_err(|_| "Failed to decrypt content key".to_string())?
+                .try_into()
+                .map_err(|_| "Invalid content key length".to_string())?,
+        };
+        let iv: [u8; 12] = decode(iv)?.try_into().map_err(|_| "Invalid IV length".to_string())?;
+
+        let mut in_out = decode(ciphertext)?;
+        in_out.extend(decode(tag)?);
+        let plaintext = aes_gcm_key(&cek)?
+            .open_in_place(Nonce::assume_unique_for_key(iv), Aad::from(header_b64.as_bytes()), &mut in_out)
+            .map_err(|_| "Failed to decrypt gift".to_string())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| "Gift is not UTF-8".to_string())
+    }
+}
+
+fn aes_gcm_key(cek: &[u8; 32]) -> Result<LessSafeKey, String> {
+    UnboundKey::new(&AES_256_GCM, cek)
+        .map(LessSafeKey::new)
+        .map_err(|_| "Invalid content key".to_string())
+}
+
+fn is_jwe(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+static GIFT_ENCRYPTION: LazyLock<Result<Option<GiftEncryption>, String>> = LazyLock::new(GiftEncryption::from_env);
+
+fn gift_encryption() -> Option<&'static GiftEncryption> {
+    GIFT_ENCRYPTION.as_ref().expect("gift encryption is checked by init").as_ref()
+}
+
+// 暗号化を求められても鍵が無ければ、黙って平文で渡さずにエラーにする
+fn encryption_for(encrypt: bool) -> Result<Option<&'static GiftEncryption>, (StatusCode, String)> {
+    match (encrypt, gift_encryption()) {
+        (false, _) => Ok(None),
+        (true, Some(encryption)) => Ok(Some(encryption)),
+        (true, None) => Err((StatusCode::BAD_REQUEST, "Gift encryption is not configured".to_string())),
+    }
+}
+
+const GIFT_COOKIE_NAME: &str = "gift";
+// 1つのCookieは属性込みで4KBまでなので余裕を持って分割する
+const COOKIE_CHUNK_SIZE: usize = 3800;
+
+fn env_flag(var: &str) -> bool {
+    std::env::var(var).is_ok_and(|value| matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+// gift Cookie の属性
+//
+// GIFT_COOKIE_HTTP_ONLY  true で HttpOnly を付ける
+// GIFT_COOKIE_SECURE     true で Secure を付ける
+// GIFT_COOKIE_SAME_SITE  Strict / Lax / None
+// GIFT_COOKIE_PATH       Path
+// GIFT_COOKIE_MAX_AGE    Max-Age (秒)
+#[derive(Debug, Clone, Default)]
+struct CookieConfig {
+    http_only: bool,
+    secure: bool,
+    same_site: Option<String>,
+    path: Option<String>,
+    max_age: Option<i64>,
+}
+
+impl CookieConfig {
+    fn from_env() -> Result<Self, String> {
+        let same_site = match std::env::var("GIFT_COOKIE_SAME_SITE") {
+            Ok(same_site) => match same_site.trim().to_ascii_lowercase().as_str() {
+                "strict" => Some("Strict".to_string()),
+                "lax" => Some("Lax".to_string()),
+                "none" => Some("None".to_string()),
+                _ => return Err(format!("GIFT_COOKIE_SAME_SITE must be Strict, Lax or None, got {}", same_site)),
+            },
+            Err(_) => None,
+        };
+        let max_age = match std::env::var("GIFT_COOKIE_MAX_AGE") {
+            Ok(_) => Some(env_seconds("GIFT_COOKIE_MAX_AGE", 0, 1)?),
+            Err(_) => None,
+        };
+        Ok(Self {
+            http_only: env_flag("GIFT_COOKIE_HTTP_ONLY"),
+            secure: env_flag("GIFT_COOKIE_SECURE"),
+            same_site,
+            path: std::env::var("GIFT_COOKIE_PATH").ok(),
+            max_age,
+        })
+    }
+
+    fn serialize(&self, name: &str, value: &str, max_age: Option<i64>) -> String {
+        let mut cookie = format!("{}={}", name, value);
+        if let Some(path) = &self.path {
+            cookie.push_str(&format!("; Path={}", path));
+        }
+        if let Some(max_age) = max_age.or(self.max_age) {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(same_site) = &self.same_site {
+            cookie.push_str(&format!("; SameSite={}", same_site));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        cookie
+    }
+
+    // 長い値は name.0, name.1, ... に分割し、分割しない名前の古いCookieは消す
+    fn set_cookies(&self, name: &str, value: &str) -> Vec<String> {
+        if value.len() <= COOKIE_CHUNK_SIZE {
+            return vec![self.serialize(name, value, None)];
+        }
+        let mut cookies: Vec<String> = value.as_bytes()
+            .chunks(COOKIE_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| self.serialize(&format!("{}.{}", name, i), std::str::from_utf8(chunk).unwrap(), None))
+            .collect();
+        cookies.push(self.serialize(name, "", Some(0)));
+        cookies
+    }
+
+    // 新しい値を設定し、それで上書きされない古い name.N だけを消す
+    // (前のgiftの分割数の方が多いと、残った name.N が新しいgiftに連結されてしまう)
+    fn replace_cookies(&self, name: &str, value: &str, headers: &HeaderMap) -> Vec<String> {
+        let chunks = match value.len() <= COOKIE_CHUNK_SIZE {
+            true => 0,
+            false => value.len().div_ceil(COOKIE_CHUNK_SIZE),
+        };
+        let mut cookies = self.set_cookies(name, value);
+        cookies.extend(
+            chunk_cookies(name, headers)
+                .into_iter()
+                .filter(|(i, _)| *i >= chunks)
+                .map(|(_, cookie)| self.serialize(&cookie, "", Some(0)))
+        );
+        cookies
+    }
+
+    // リクエストに来ている name と name.N を全て消す
+    fn clear_cookies(&self, name: &str, headers: &HeaderMap) -> Vec<String> {
+        let mut names: Vec<String> = chunk_cookies(name, headers).into_iter().map(|(_, cookie)| cookie).collect();
+        names.push(name.to_string());
+        names.iter().map(|cookie| self.serialize(cookie, "", Some(0))).collect()
+    }
+}
+
+// リクエストに来ている name.N を N の順に返す
+fn chunk_cookies(name: &str, headers: &HeaderMap) -> Vec<(usize, String)> {
+    let prefix = format!("{}.", name);
+    let mut chunks: Vec<(usize, String)> = parse_cookies(headers)
+        .into_keys()
+        .filter_map(|cookie| Some((cookie.strip_prefix(&prefix)?.parse().ok()?, cookie)))
+        .collect();
+    chunks.sort();
+    chunks
+}
+
+static GIFT_COOKIE: LazyLock<Result<CookieConfig, String>> = LazyLock::new(CookieConfig::from_env);
+
+fn gift_cookie() -> &'static CookieConfig {
+    GIFT_COOKIE.as_ref().expect("gift cookie attributes are checked by init")
+}
+
+// 全ての Cookie ヘッダーを `name=value; name=value` として読む(同名は先勝ち)
+fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for header in headers.get_all(COOKIE) {
+        let Ok(header) = header.to_str() else {
+            continue;
+        };
+        for pair in header.split(';') {
+            if let Some((name, value)) = pair.trim().split_once('=') {
+                let value = value.trim().trim_matches('"');
+                cookies.entry(name.trim().to_string()).or_insert(value.to_string());
+            }
+        }
+    }
+    cookies
+}
+
+// 分割されたCookieも連結して返す
+fn read_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    let cookies = parse_cookies(headers);
+    if let Some(value) = cookies.get(name).filter(|value| !value.is_empty()) {
+        return Some(value.clone());
+    }
+    let chunks: Vec<&String> = (0..)
+        .map_while(|i| cookies.get(&format!("{}.{}", name, i)))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+    Some(chunks.into_iter().map(String::as_str).collect())
+}
+
+fn set_cookie_headers(cookies: Vec<String>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            headers.append(SET_COOKIE, value);
+        }
+    }
+    headers
+}
+
+// アクティブな鍵で署名し、必要ならJWEで包む
+fn issue_gift(data: Value, custom: GiftClaims, ttl: i64, encryption: Option<&GiftEncryption>) -> Result<String, String> {
+    let exp = (Utc::now() + Duration::seconds(ttl)).timestamp() as usize;
     let claims = Claims {
-        data: body,
+        data,
         exp,
+        jti: Uuid::new_v4().to_string(),
+        custom,
     };
-    
+
+    let key = gift_keyring().active();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+
     let token = encode(
-        &Header::default(),
+        &header,
         &claims,
-        &EncodingKey::from_secret(JWT_SECRET)
+        key.encoding.as_ref().unwrap()
     ).unwrap();
 
-    let cookie = format!("gift={}", token);
-    
+    match encryption {
+        Some(encryption) => encryption.encrypt(&token),
+        None => Ok(token),
+    }
+}
+
+fn gift_ttl(query: &WrapQuery) -> Option<i64> {
+    let ttl = query.expires_in.unwrap_or(DEFAULT_TOKEN_TTL).min(max_token_ttl());
+    (ttl > 0).then_some(ttl)
+}
+
+struct Gift {
+    claims: Value,
+    encrypted: bool,
+}
+
+impl Gift {
+    // jti の無いトークンは jti 導入前に発行されたもの
+    fn jti(&self) -> Option<&str> {
+        self.claims.get("jti").and_then(Value::as_str)
+    }
+
+    fn expires_at(&self) -> DateTime<Utc> {
+        self.claims.get("exp")
+            .and_then(Value::as_i64)
+            .and_then(|exp| DateTime::from_timestamp(exp, 0))
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+// unwrap 側が求める宛先など。aud 付きのgiftは aud を指定しないと開けない
+#[derive(Debug, Default, Deserialize)]
+pub struct GiftExpectations {
+    aud: Option<String>,
+    sub: Option<String>,
+    iss: Option<String>,
+}
+
+impl GiftExpectations {
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_nbf = true;
+        if let Some(aud) = &self.aud {
+            validation.set_audience(&[aud]);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        if let Some(sub) = &self.sub {
+            validation.sub = Some(sub.clone());
+            validation.required_spec_claims.insert("sub".to_string());
+        }
+        if let Some(iss) = &self.iss {
+            validation.set_issuer(&[iss]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        validation
+    }
+}
+
+// Cookie のgiftを復号・検証し、失効していないことを確かめる
+async fn verify_gift(state: &StateGifts, headers: &HeaderMap, expected: &GiftExpectations) -> Result<Gift, (StatusCode, String)> {
+    let bad_request = || (StatusCode::BAD_REQUEST, "".to_string());
+
+    let token = read_cookie(headers, GIFT_COOKIE_NAME).ok_or_else(bad_request)?;
+
+    // 暗号化されたgiftは復号してから署名を検証する
+    let encrypted = is_jwe(&token);
+    let token = if encrypted {
+        gift_encryption()
+            .and_then(|encryption| encryption.decrypt(&token).ok())
+            .ok_or_else(bad_request)?
+    } else {
+        token
+    };
+
+    let key = decode_header(&token).ok()
+        .and_then(|header| gift_keyring().find(header.kid.as_deref()))
+        .ok_or_else(bad_request)?;
+
+    let claims = decode::<Value>(
+        &token,
+        &key.decoding,
+        &expected.validation(key.algorithm)
+    ).map_err(|e| match e.kind() {
+        ErrorKind::InvalidAudience | ErrorKind::InvalidSubject | ErrorKind::InvalidIssuer | ErrorKind::MissingRequiredClaim(_) => {
+            (StatusCode::FORBIDDEN, format!("Gift is not for you: {}", e))
+        },
+        ErrorKind::ImmatureSignature => (StatusCode::FORBIDDEN, "Gift cannot be opened yet".to_string()),
+        _ => bad_request(),
+    })?.claims;
+    let gift = Gift { claims, encrypted };
+
+    if let Some(jti) = gift.jti() {
+        match state.is_revoked(jti).await {
+            Ok(false) => {},
+            Ok(true) => return Err((StatusCode::UNAUTHORIZED, "Gift has been revoked".to_string())),
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))),
+        }
+    }
+    Ok(gift)
+}
+
+#[derive(Deserialize)]
+pub struct WrapQuery {
+    expires_in: Option<i64>,
+    // 未指定なら GIFT_ENCRYPT に従う
+    encrypt: Option<bool>,
+    sub: Option<String>,
+    // カンマ区切りで複数指定できる
+    aud: Option<String>,
+    // UNIX時刻
+    nbf: Option<i64>,
+    iss: Option<String>,
+    // プライベートクレーム (JSONオブジェクト)
+    claims: Option<String>,
+}
+
+pub async fn wrap(
+    Query(query): Query<WrapQuery>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let Some(ttl) = gift_ttl(&query) else {
+        return (StatusCode::BAD_REQUEST, "Invalid expires_in".to_string()).into_response();
+    };
+
+    let custom = match GiftClaims::from_query(&query) {
+        Ok(custom) => custom,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let encryption = match encryption_for(query.encrypt.unwrap_or_else(|| env_flag("GIFT_ENCRYPT"))) {
+        Ok(encryption) => encryption,
+        Err(e) => return e.into_response(),
+    };
+    let token = match issue_gift(body, custom, ttl, encryption) {
+        Ok(token) => token,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    };
+
     (
         StatusCode::OK,
-        [(SET_COOKIE, cookie)]
-    )
+        set_cookie_headers(gift_cookie().replace_cookies(GIFT_COOKIE_NAME, &token, &headers))
+    ).into_response()
 }
 
 pub async fn unwrap(
-    cookie: Option<HeaderMap>,
+    State(state): State<StateGifts>,
+    Query(expected): Query<GiftExpectations>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let cookie = if let Some(cookie) = cookie{
-        cookie
-    } else {
-        return (StatusCode::BAD_REQUEST, "".to_string());
+    let gift = match verify_gift(&state, &headers, &expected).await {
+        Ok(gift) => gift,
+        Err(e) => return e,
     };
 
-    let token = if let Some(token) = cookie.get("cookie") {
-        token
-    } else {
-        return (StatusCode::BAD_REQUEST, "".to_string());
+    (
+        StatusCode::OK,
+        gift.claims.get("data").unwrap().to_string()
+    )
+}
+
+// 有効なgiftを、新しい jti と有効期限のgiftに取り替える(sub や aud などは引き継ぐ)
+pub async fn refresh(
+    State(state): State<StateGifts>,
+    Query(query): Query<WrapQuery>,
+    Query(expected): Query<GiftExpectations>,
+    headers: HeaderMap,
+) -> Response {
+    let gift = match verify_gift(&state, &headers, &expected).await {
+        Ok(gift) => gift,
+        Err(e) => return e.into_response(),
     };
-    let token = if let Ok(token) = token.to_str() {
-        token
-    } else {
-        return (StatusCode::BAD_REQUEST, "".to_string());
+    let Some(ttl) = gift_ttl(&query) else {
+        return (StatusCode::BAD_REQUEST, "Invalid expires_in".to_string()).into_response();
     };
 
-    let token = token.replace("gift=", "");
-    
-    let claims = if let Ok(token_data) = decode::<Value>(
-        &token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &Validation::default()
-    ) {
-        token_data.claims
-    } else {
-        return (StatusCode::BAD_REQUEST, "".to_string());
+    let data = gift.claims.get("data").cloned().unwrap_or(Value::Null);
+    let custom = GiftClaims::from_claims(&gift.claims);
+    let encryption = match encryption_for(query.encrypt.unwrap_or(gift.encrypted)) {
+        Ok(encryption) => encryption,
+        Err(e) => return e.into_response(),
+    };
+    let token = match issue_gift(data, custom, ttl, encryption) {
+        Ok(token) => token,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
     };
 
+    // 古いgiftはもう使えないようにする
+    if let Some(jti) = gift.jti() {
+        if let Err(e) = state.revoke(jti, gift.expires_at()).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response();
+        }
+    }
+
     (
         StatusCode::OK,
-        claims.get("data").unwrap().to_string()
-    )
+        set_cookie_headers(gift_cookie().replace_cookies(GIFT_COOKIE_NAME, &token, &headers))
+    ).into_response()
 }
 
-const SANTA_JWT_SECRET: &[u8] = include_bytes!("day16_santa_public_key.pem");
-use std::sync::LazyLock;
+pub async fn revoke(
+    State(state): State<StateGifts>,
+    Query(expected): Query<GiftExpectations>,
+    headers: HeaderMap,
+) -> Response {
+    let gift = match verify_gift(&state, &headers, &expected).await {
+        Ok(gift) => gift,
+        Err(e) => return e.into_response(),
+    };
+    let Some(jti) = gift.jti() else {
+        return (StatusCode::BAD_REQUEST, "Gift has no jti".to_string()).into_response();
+    };
 
-static SANTA_ENCODING_KEY: LazyLock<DecodingKey> = LazyLock::new(|| {
-    DecodingKey::from_rsa_pem(SANTA_JWT_SECRET).unwrap()
-});
+    if let Err(e) = state.revoke(jti, gift.expires_at()).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response();
+    }
 
-pub async fn decode_santa(
-    body_bytes: Bytes
-) -> impl IntoResponse {
-    let body = body_bytes.to_vec();
-    let body = if let Ok(body) = String::from_utf8(body) {
+    (
+        StatusCode::OK,
+        set_cookie_headers(gift_cookie().clear_cookies(GIFT_COOKIE_NAME, &headers))
+    ).into_response()
+}
+
+#[derive(Serialize)]
+struct ExpiryStatus {
+    exp: Option<i64>,
+    expires_at: Option<String>,
+    expired: Option<bool>,
+    remaining_seconds: Option<i64>,
+}
+
+impl ExpiryStatus {
+    fn from_claims(claims: &Value) -> Self {
+        let exp = claims.get("exp").and_then(Value::as_i64);
+        let expires_at = exp.and_then(|exp| DateTime::from_timestamp(exp, 0));
+        let remaining = expires_at.map(|expires_at| (expires_at - Utc::now()).num_seconds());
+        Self {
+            exp,
+            expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+            expired: remaining.map(|remaining| remaining <= 0),
+            remaining_seconds: remaining.map(|remaining| remaining.max(0)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SignatureStatus {
+    verified: bool,
+    kid: Option<String>,
+    error: Option<String>,
+}
+
+impl SignatureStatus {
+    // 有効期限などは別に報告するので、ここでは署名だけを見る
+    fn check(token: &str, kid: Option<&str>) -> Self {
+        let Some(key) = gift_keyring().find(kid) else {
+            return Self {
+                verified: false,
+                kid: None,
+                error: Some(format!("No configured key for kid {}", kid.unwrap_or_default())),
+            };
+        };
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+        validation.required_spec_claims = HashSet::new();
+
+        let error = decode::<Value>(token, &key.decoding, &validation).err();
+        Self {
+            verified: error.is_none(),
+            kid: Some(key.kid.clone()),
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Inspection {
+    encrypted: bool,
+    header: Value,
+    algorithm: Option<String>,
+    kid: Option<String>,
+    claims: Value,
+    expiry: ExpiryStatus,
+    signature: SignatureStatus,
+    // jti の無いトークンや、DBに問い合わせられなかったときは null
+    revoked: Option<bool>,
+}
+
+fn inspect_error(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+// トークンを信用せずに中身を見せる。本文が空なら gift Cookie を見る
+pub async fn inspect(
+    State(state): State<StateGifts>,
+    headers: HeaderMap,
+    body_bytes: Bytes,
+) -> Response {
+    let body = String::from_utf8_lossy(&body_bytes).trim().to_string();
+    let token = if !body.is_empty() {
         body
+    } else if let Some(token) = read_cookie(&headers, GIFT_COOKIE_NAME) {
+        token
     } else {
-        return (StatusCode::BAD_REQUEST, "".to_string());
-    };
-
-    let mut validation = Validation::new(Algorithm::RS256);
-    validation.validate_exp = false;
-    validation.required_spec_claims = HashSet::new();
-
-    let algorithms = vec![
-        Algorithm::HS256,
-        Algorithm::HS384,
-        Algorithm::HS512,
-        Algorithm::RS256,
-        Algorithm::RS384,
-        Algorithm::RS512,
-        Algorithm::ES256,
-        Algorithm::ES384,
-        Algorithm::PS256,
-        Algorithm::PS256,
-        Algorithm::PS384,
-        Algorithm::PS512,
-    ];
-
-    let mut is_bad_request = false;
-    for alg in algorithms {
-        validation.algorithms = vec![alg];
-        match decode::<Value>(
-            &body,
-            &SANTA_ENCODING_KEY,
-            &validation
-        ) {
-            Ok(token_data) => {
-                //println!("{:?}", token_data.claims);
-                return (
-                    StatusCode::OK,
-                    token_data.claims.to_string()
-                );
+        return inspect_error("No token provided".to_string());
+    };
+
+    let encrypted = is_jwe(&token);
+    let token = if encrypted {
+        match gift_encryption().map(|encryption| encryption.decrypt(&token)) {
+            Some(Ok(token)) => token,
+            Some(Err(e)) => return inspect_error(e),
+            None => return inspect_error("Gift encryption is not configured".to_string()),
+        }
+    } else {
+        token
+    };
+
+    let (header, claims) = match (peek_part(&token, 0), peek_claims(&token)) {
+        (Ok(header), Ok(claims)) => (header, claims),
+        (Err(DecodeError::Malformed(e)), _) | (_, Err(DecodeError::Malformed(e))) => return inspect_error(e),
+        _ => return inspect_error("Malformed token".to_string()),
+    };
+    let algorithm = header.get("alg").and_then(Value::as_str).map(str::to_string);
+    let kid = header.get("kid").and_then(Value::as_str).map(str::to_string);
+
+    let revoked = match claims.get("jti").and_then(Value::as_str) {
+        Some(jti) => state.is_revoked(jti).await.ok(),
+        None => None,
+    };
+
+    Json(Inspection {
+        encrypted,
+        expiry: ExpiryStatus::from_claims(&claims),
+        signature: SignatureStatus::check(&token, kid.as_deref()),
+        header,
+        algorithm,
+        kid,
+        claims,
+        revoked,
+    }).into_response()
+}
+
+pub async fn jwks() -> impl IntoResponse {
+    Json(gift_keyring().jwks())
+}
+
+const SANTA_JWT_SECRET: &[u8] = include_bytes!("day16_santa_public_key.pem");
+
+// RSA鍵で検証できるアルゴリズムだけを許可する(HS* で公開鍵を共有鍵扱いさせない)
+const RSA_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+
+// 検証鍵と、その鍵で受け付けるアルゴリズム
+struct VerifyingKey {
+    kid: Option<String>,
+    decoding: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+impl VerifyingKey {
+    fn allows(&self, algorithm: Algorithm) -> bool {
+        self.algorithms.contains(&algorithm)
+    }
+
+    // kid を持たない鍵はどの kid のトークンにも使う
+    fn matches(&self, kid: Option<&str>) -> bool {
+        match (kid, self.kid.as_deref()) {
+            (Some(kid), Some(own)) => kid == own,
+            _ => true,
+        }
+    }
+
+    // 署名を検証する前に、ヘッダーの alg が鍵の方針に合うかを見る
+    fn verify(&self, token: &str, algorithm: Algorithm, validation: &Validation) -> Result<Value, DecodeError> {
+        if !self.allows(algorithm) {
+            return Err(DecodeError::AlgorithmNotAllowed(algorithm));
+        }
+        let mut validation = validation.clone();
+        validation.algorithms = vec![algorithm];
+        decode::<Value>(token, &self.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| match e.kind() {
+                // alg が鍵の種類と合わない
+                ErrorKind::InvalidAlgorithm => DecodeError::AlgorithmNotAllowed(algorithm),
+                _ => e.into(),
+            })
+    }
+
+    fn from_pem(kid: Option<String>, algorithms: Vec<Algorithm>, pem: &[u8]) -> Result<Self, String> {
+        let decoding = match algorithms.first() {
+            Some(alg) if RSA_ALGORITHMS.contains(alg) => DecodingKey::from_rsa_pem(pem),
+            Some(Algorithm::ES256 | Algorithm::ES384) => DecodingKey::from_ec_pem(pem),
+            Some(Algorithm::EdDSA) => DecodingKey::from_ed_pem(pem),
+            Some(alg) => return Err(format!("{:?} cannot be used with a public key", alg)),
+            None => return Err("algorithms must not be empty".to_string()),
+        }.map_err(|e| format!("Invalid public key: {}", e))?;
+        if algorithms.iter().any(|alg| key_family(*alg) != key_family(algorithms[0])) {
+            return Err("algorithms must share one key type".to_string());
+        }
+        Ok(Self { kid, decoding, algorithms })
+    }
+
+    // alg の無いJWKは鍵の種類から許可するアルゴリズムを決める
+    fn from_jwk(jwk: &Jwk) -> Result<Self, String> {
+        let algorithms = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (Some(alg), _) => vec![Algorithm::from_str(&alg.to_string()).map_err(|_| format!("{} is not a signing algorithm", alg))?],
+            (None, AlgorithmParameters::RSA(_)) => RSA_ALGORITHMS.to_vec(),
+            (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+                EllipticCurve::P256 => vec![Algorithm::ES256],
+                EllipticCurve::P384 => vec![Algorithm::ES384],
+                _ => return Err(format!("Unsupported curve {:?}", params.curve)),
+            },
+            (None, AlgorithmParameters::OctetKeyPair(_)) => vec![Algorithm::EdDSA],
+            (None, AlgorithmParameters::OctetKey(_)) => return Err("Symmetric keys cannot be trusted".to_string()),
+        };
+        if algorithms.iter().any(|alg| key_family(*alg) == "HMAC") {
+            return Err("Symmetric keys cannot be trusted".to_string());
+        }
+        let decoding = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid JWK: {}", e))?;
+        Ok(Self { kid: jwk.common.key_id.clone(), decoding, algorithms })
+    }
+}
+
+// JWKSには暗号化用の鍵 (use: enc や alg: RSA-OAEP) も並ぶことがある
+fn is_signing_key(jwk: &Value) -> bool {
+    if jwk.get("use").and_then(Value::as_str) == Some("enc") {
+        return false;
+    }
+    match jwk.get("alg").and_then(Value::as_str) {
+        Some(alg) => Algorithm::from_str(alg).is_ok(),
+        None => true,
+    }
+}
+
+fn key_family(algorithm: Algorithm) -> &'static str {
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => "HMAC",
+        Algorithm::ES256 | Algorithm::ES384 => "EC",
+        Algorithm::EdDSA => "OKP",
+        _ => "RSA",
+    }
+}
+
+// SANTA_JWT_ALGORITHMS でさらに絞れる (例: RS256,PS256)
+fn santa_key_from_env() -> Result<VerifyingKey, String> {
+    let algorithms = match std::env::var("SANTA_JWT_ALGORITHMS") {
+        Ok(algorithms) => algorithms.split(',')
+            .map(|alg| Algorithm::from_str(alg.trim()).map_err(|_| format!("Unknown algorithm {}", alg.trim())))
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(_) => RSA_ALGORITHMS.to_vec(),
+    };
+    if let Some(alg) = algorithms.iter().find(|alg| !RSA_ALGORITHMS.contains(alg)) {
+        return Err(format!("{:?} cannot be used with an RSA key", alg));
+    }
+    VerifyingKey::from_pem(None, algorithms, SANTA_JWT_SECRET)
+}
+
+static SANTA_KEY: LazyLock<Result<VerifyingKey, String>> = LazyLock::new(santa_key_from_env);
+
+fn santa_key() -> &'static VerifyingKey {
+    SANTA_KEY.as_ref().expect("santa key is checked by init")
+}
+
+// 信頼する発行者の設定 (JWT_TRUSTED_ISSUERS のTOMLファイル)
+//
+// [[issuers]]
+// iss = "https://auth.example.internal"
+// jwks = "keys/auth.jwks.json"
+// audience = ["santa"]
+// required_claims = ["sub", "exp"]
+//
+// [[issuers]]
+// iss = "workshop"
+// public_key = "keys/workshop.pem"
+// kid = "workshop-1"
+// algorithms = ["ES256"]
+#[derive(Debug, Deserialize)]
+struct IssuerConfig {
+    iss: String,
+    public_key: Option<String>,
+    jwks: Option<String>,
+    kid: Option<String>,
+    #[serde(default)]
+    algorithms: Vec<String>,
+    #[serde(default)]
+    audience: Vec<String>,
+    #[serde(default)]
+    required_claims: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct IssuerRegistryConfig {
+    #[serde(default)]
+    issuers: Vec<IssuerConfig>,
+}
+
+struct TrustedIssuer {
+    iss: String,
+    keys: Vec<VerifyingKey>,
+    audience: Vec<String>,
+    required_claims: Vec<String>,
+}
+
+impl TrustedIssuer {
+    fn from_config(config: IssuerConfig) -> Result<Self, String> {
+        let keys = match (&config.public_key, &config.jwks) {
+            (Some(public_key), None) => {
+                let algorithms = config.algorithms.iter()
+                    .map(|alg| Algorithm::from_str(alg).map_err(|_| format!("Unknown algorithm {}", alg)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                vec![VerifyingKey::from_pem(config.kid.clone(), algorithms, &load_pem(public_key)?)?]
             },
-            Err(e) => {
-                //println!("Failed to decode with {:?}: {:?}", alg, e);
-                match e.kind() {
-                    ErrorKind::InvalidToken | ErrorKind::Json(_) | ErrorKind::Base64(_) => {
-                        is_bad_request = true;
-                    },
-                    _ => {}
+            (None, Some(path)) => {
+                let jwks = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                // 未知の alg の鍵があってもJWKS全体を捨てないよう、1つずつ読む
+                let jwks: Value = serde_json::from_str(&jwks).map_err(|e| format!("Invalid JWKS {}: {}", path, e))?;
+                let keys = jwks.get("keys")
+                    .and_then(Value::as_array)
+                    .ok_or(format!("Invalid JWKS {}: keys is missing", path))?
+                    .iter()
+                    .filter(|jwk| is_signing_key(jwk))
+                    .map(|jwk| {
+                        let jwk: Jwk = serde_json::from_value(jwk.clone()).map_err(|e| format!("Invalid JWK in {}: {}", path, e))?;
+                        VerifyingKey::from_jwk(&jwk)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if keys.is_empty() {
+                    return Err(format!("{} has no signing keys in {}", config.iss, path));
                 }
+                keys
+            },
+            _ => return Err(format!("{} needs exactly one of public_key or jwks", config.iss)),
+        };
+        Ok(Self {
+            iss: config.iss,
+            keys,
+            audience: config.audience,
+            required_claims: config.required_claims,
+        })
+    }
+
+    fn decode(&self, token: &str, header: &Header) -> Result<Value, DecodeError> {
+        let mut validation = Validation::new(header.alg);
+        validation.required_spec_claims = HashSet::new();
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.iss]);
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audience);
+        }
+
+        let keys: Vec<&VerifyingKey> = self.keys.iter().filter(|key| key.matches(header.kid.as_deref())).collect();
+        if keys.is_empty() {
+            return Err(DecodeError::UnknownKey(header.kid.clone().unwrap_or_default()));
+        }
+        // 同じ kid の鍵が複数あっても、どれも許可された alg でしか試さない
+        let mut result = Err(DecodeError::AlgorithmNotAllowed(header.alg));
+        for key in keys.into_iter().filter(|key| key.allows(header.alg)) {
+            result = key.verify(token, header.alg, &validation);
+            if result.is_ok() {
+                break;
             }
         }
+        let claims = result?;
+
+        if let Some(claim) = self.required_claims.iter().find(|claim| claims.get(claim.as_str()).is_none()) {
+            return Err(DecodeError::InvalidClaims(format!("Missing required claim {}", claim)));
+        }
+        Ok(claims)
     }
-    if is_bad_request {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to decode with all algorithms".to_string()
-        )
-    } else {
-        (
-            StatusCode::UNAUTHORIZED,
-            "Failed to decode".to_string()
-        )
+}
+
+struct IssuerRegistry {
+    issuers: HashMap<String, TrustedIssuer>,
+}
+
+impl IssuerRegistry {
+    fn from_env() -> Result<Self, String> {
+        let config = match std::env::var("JWT_TRUSTED_ISSUERS") {
+            Ok(path) => {
+                let config = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                toml::from_str(&config).map_err(|e| format!("Invalid issuer registry: {}", e))?
+            },
+            Err(_) => IssuerRegistryConfig::default(),
+        };
+        let issuers = config.issuers.into_iter()
+            .map(|config| TrustedIssuer::from_config(config).map(|issuer| (issuer.iss.clone(), issuer)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(Self { issuers })
+    }
+
+    fn get(&self, iss: &str) -> Option<&TrustedIssuer> {
+        self.issuers.get(iss)
     }
-}
\ No newline at end of file
+}
+
+static TRUSTED_ISSUERS: LazyLock<Result<IssuerRegistry, String>> = LazyLock::new(IssuerRegistry::from_env);
+
+fn trusted_issuers() -> &'static IssuerRegistry {
+    TRUSTED_ISSUERS.as_ref().expect("trusted issuers are checked by init")
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    // JWTとして読めない
+    Malformed(String),
+    // ヘッダーの alg がこの鍵では許可されていない
+    AlgorithmNotAllowed(Algorithm),
+    InvalidSignature,
+    // 署名は正しいがクレームの検証に失敗した
+    InvalidClaims(String),
+    UnknownIssuer(String),
+    UnknownKey(String),
+    // サーバー側の検証鍵が使えない
+    InvalidKey(String),
+}
+
+impl From<jsonwebtoken::errors::Error> for DecodeError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            ErrorKind::InvalidToken | ErrorKind::Base64(_) | ErrorKind::Json(_) | ErrorKind::Utf8(_) => Self::Malformed(e.to_string()),
+            ErrorKind::InvalidSignature => Self::InvalidSignature,
+            ErrorKind::InvalidAlgorithm | ErrorKind::InvalidKeyFormat | ErrorKind::InvalidRsaKey(_) | ErrorKind::InvalidEcdsaKey => Self::InvalidKey(e.to_string()),
+            _ => Self::InvalidClaims(e.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for DecodeError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Malformed(e) => (StatusCode::BAD_REQUEST, format!("Malformed token: {}", e)),
+            Self::AlgorithmNotAllowed(alg) => (StatusCode::UNAUTHORIZED, format!("Algorithm {:?} is not allowed", alg)),
+            Self::InvalidSignature => (StatusCode::UNAUTHORIZED, "Invalid signature".to_string()),
+            Self::InvalidClaims(e) => (StatusCode::UNAUTHORIZED, format!("Invalid claims: {}", e)),
+            Self::UnknownIssuer(iss) => (StatusCode::UNAUTHORIZED, format!("Unknown issuer {}", iss)),
+            Self::UnknownKey(kid) => (StatusCode::UNAUTHORIZED, format!("Unknown key {}", kid)),
+            Self::InvalidKey(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid verification key: {}", e)),
+        }.into_response()
+    }
+}
+
+// 署名を検証せずに index 番目の部分をJSONとして読む
+fn peek_part(token: &str, index: usize) -> Result<Value, DecodeError> {
+    let part = token.split('.').nth(index).ok_or(DecodeError::Malformed("Token has too few parts".to_string()))?;
+    let part = URL_SAFE_NO_PAD.decode(part).map_err(|e| DecodeError::Malformed(e.to_string()))?;
+    serde_json::from_slice(&part).map_err(|e| DecodeError::Malformed(e.to_string()))
+}
+
+// 発行者を選ぶためにペイロードだけを先に読む
+fn peek_claims(token: &str) -> Result<Value, DecodeError> {
+    peek_part(token, 1)
+}
+
+// iss の無いトークンは従来どおり Santa の鍵で検証する
+fn decode_external(token: &str) -> Result<Value, DecodeError> {
+    let header = decode_header(token)?;
+    match peek_claims(token)?.get("iss") {
+        None => {
+            let mut validation = Validation::new(header.alg);
+            validation.validate_exp = false;
+            validation.required_spec_claims = HashSet::new();
+            santa_key().verify(token, header.alg, &validation)
+        },
+        Some(Value::String(iss)) => trusted_issuers().get(iss)
+            .ok_or(DecodeError::UnknownIssuer(iss.clone()))?
+            .decode(token, &header),
+        Some(_) => Err(DecodeError::Malformed("iss must be a string".to_string())),
+    }
+}
+
+pub async fn decode_santa(
+    body_bytes: Bytes
+) -> Result<String, DecodeError> {
+    let body = String::from_utf8(body_bytes.to_vec())
+        .map_err(|_| DecodeError::Malformed("Token is not UTF-8".to_string()))?;
+
+    let claims = decode_external(body.trim())?;
+    Ok(claims.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_cookies(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn replaces_unchunked_gift() {
+        let config = CookieConfig::default();
+        let headers = request_cookies("gift.0=a; gift.1=b; gift=old");
+        assert_eq!(
+            config.replace_cookies("gift", "token", &headers),
+            vec!["gift=token", "gift.0=; Max-Age=0", "gift.1=; Max-Age=0"]
+        );
+        assert_eq!(config.replace_cookies("gift", "token", &HeaderMap::new()), vec!["gift=token"]);
+    }
+
+    #[test]
+    fn replaces_chunked_gift() {
+        let config = CookieConfig::default();
+        let token = "a".repeat(COOKIE_CHUNK_SIZE) + "b";
+        let headers = request_cookies("gift.0=x; gift.1=y; gift.2=z");
+        assert_eq!(
+            config.replace_cookies("gift", &token, &headers),
+            vec![
+                format!("gift.0={}", "a".repeat(COOKIE_CHUNK_SIZE)),
+                "gift.1=b".to_string(),
+                "gift=; Max-Age=0".to_string(),
+                "gift.2=; Max-Age=0".to_string(),
+            ]
+        );
+    }
+    fn direct() -> GiftEncryption {
+        GiftEncryption { key: ContentKey::Direct([7; 32]) }
+    }
+
+    fn rsa_oaep() -> GiftEncryption {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        GiftEncryption { key: ContentKey::RsaOaep(Box::new(key)) }
+    }
+
+    // i 番目の部分を差し替えたJWE
+    fn replace_part(token: &str, i: usize, part: &str) -> String {
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[i] = part;
+        parts.join(".")
+    }
+
+    #[test]
+    fn round_trips_jwe() {
+        for encryption in [direct(), rsa_oaep()] {
+            let token = encryption.encrypt("header.claims.signature").unwrap();
+            assert!(is_jwe(&token));
+            assert_eq!(encryption.decrypt(&token).unwrap(), "header.claims.signature");
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_jwe() {
+        for encryption in [direct(), rsa_oaep()] {
+            let token = encryption.encrypt("header.claims.signature").unwrap();
+            let parts: Vec<&str> = token.split('.').collect();
+
+            let mut ciphertext = URL_SAFE_NO_PAD.decode(parts[3]).unwrap();
+            ciphertext[0] ^= 1;
+            assert!(encryption.decrypt(&replace_part(&token, 3, &URL_SAFE_NO_PAD.encode(ciphertext))).is_err());
+
+            let mut tag = URL_SAFE_NO_PAD.decode(parts[4]).unwrap();
+            tag[0] ^= 1;
+            assert!(encryption.decrypt(&replace_part(&token, 4, &URL_SAFE_NO_PAD.encode(tag))).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_jwe_header() {
+        let encryption = direct();
+        let token = encryption.encrypt("header.claims.signature").unwrap();
+
+        // 別の鍵管理方式で作られたJWEは受け付けない
+        assert!(rsa_oaep().decrypt(&token).is_err());
+
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"dir","enc":"A128GCM"}"#);
+        assert!(encryption.decrypt(&replace_part(&token, 0, &header)).is_err());
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RSA-OAEP","enc":"A256GCM"}"#);
+        assert!(encryption.decrypt(&replace_part(&token, 0, &header)).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_iv_length() {
+        let encryption = direct();
+        let token = encryption.encrypt("header.claims.signature").unwrap();
+        let iv = URL_SAFE_NO_PAD.encode([0u8; 8]);
+        assert_eq!(encryption.decrypt(&replace_part(&token, 2, &iv)), Err("Invalid IV length".to_string()));
+    }
+}
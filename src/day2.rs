@@ -1,86 +1,892 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use axum::extract::Query;
-use serde::Deserialize;
+use axum::{
+    body::Bytes,
+    extract::Query,
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
 
 
-// task 1
+// task 1, task 3
+//
+// IPv4 / IPv6 のどちらも受け付ける。/2/v6/dest はこのルートの別名
 #[derive(Deserialize)]
 pub struct FromKeyQuery {
-    from: Ipv4Addr,
-    key: Ipv4Addr,
+    from: IpAddr,
+    key: IpAddr,
+    scheme: Option<Scheme>,
 }
 
-pub async fn from_key_calc(from_key_query: Query<FromKeyQuery>) -> String {
-    let from = from_key_query.from;
-    let key = from_key_query.key;
+pub async fn from_key_calc(from_key_query: Query<FromKeyQuery>) -> Result<String, (StatusCode, String)> {
+    let (family, from, key) = pair_addrs(("from", from_key_query.from), ("key", from_key_query.key))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let cipher = Scheme::cipher_for(from_key_query.scheme, family);
 
-    Ipv4Addr::new(from.octets()[0].overflowing_add(key.octets()[0]).0,
-                    from.octets()[1].overflowing_add(key.octets()[1]).0,
-                    from.octets()[2].overflowing_add(key.octets()[2]).0,
-                    from.octets()[3].overflowing_add(key.octets()[3]).0).to_string()
-                  .to_string()
+    Ok(format_like(family, cipher.encrypt(family, from, key), from_key_query.from))
 }
 
-// task 2
+// task 2, task 3
+//
+// /2/v6/key はこのルートの別名。from の代わりに key を渡すと to と key から from を求める
 #[derive(Deserialize)]
 pub struct FromToQuery {
-    from: Ipv4Addr,
-    to: Ipv4Addr,
+    from: Option<IpAddr>,
+    to: IpAddr,
+    key: Option<IpAddr>,
+    scheme: Option<Scheme>,
 }
 
-pub async fn from_to_calc(from_to_query: Query<FromToQuery>) -> String {
-    let from = from_to_query.from;
-    let to = from_to_query.to;
+pub async fn from_to_calc(from_to_query: Query<FromToQuery>) -> Result<String, (StatusCode, String)> {
+    let to_addr = from_to_query.to;
+    match (from_to_query.from, from_to_query.key) {
+        (Some(from_addr), None) => {
+            let (family, from, to) = pair_addrs(("from", from_addr), ("to", to_addr))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            Scheme::cipher_for(from_to_query.scheme, family)
+                .recover_key(family, from, to)
+                .map(|key| family.format(key))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))
+        },
+        (None, Some(key_addr)) => {
+            let (family, to, key) = pair_addrs(("to", to_addr), ("key", key_addr))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            let cipher = Scheme::cipher_for(from_to_query.scheme, family);
+            Ok(format_like(family, cipher.decrypt(family, to, key), to_addr))
+        },
+        _ => Err((StatusCode::BAD_REQUEST, ONE_OF_FROM_OR_KEY.to_string())),
+    }
+}
+
+const ONE_OF_FROM_OR_KEY: &str = "Exactly one of from or key is required";
+
+// アドレス範囲 (CIDR / 範囲) の暗号化
+//
+// IPv4 は 8bit x 4桁、IPv6 は 16bit x 8桁、MAC-48 / EUI-64 は 8bit x 6桁 / 8桁として扱う。
+// アドレスはどれも u128 で持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Family {
+    V4,
+    V6,
+    Mac48,
+    Eui64,
+}
+
+impl Family {
+    fn digits(self) -> usize {
+        match self {
+            Family::V4 => 4,
+            Family::V6 => 8,
+            Family::Mac48 => 6,
+            Family::Eui64 => 8,
+        }
+    }
+
+    fn digit_bits(self) -> u32 {
+        match self {
+            Family::V6 => 16,
+            _ => 8,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        self.digits() as u32 * self.digit_bits()
+    }
+
+    fn digit_max(self) -> u32 {
+        (1 << self.digit_bits()) - 1
+    }
+
+    fn mask(self) -> u128 {
+        u128::MAX >> (128 - self.bits())
+    }
+
+    fn split(self, addr: u128) -> Vec<u32> {
+        (0..self.digits())
+            .rev()
+            .map(|i| ((addr >> (i as u32 * self.digit_bits())) as u32) & self.digit_max())
+            .collect()
+    }
+
+    fn join(self, digits: &[u32]) -> u128 {
+        digits.iter().fold(0, |addr, &digit| (addr << self.digit_bits()) | digit as u128)
+    }
+
+    fn format(self, addr: u128) -> String {
+        match self {
+            Family::V4 => Ipv4Addr::from(addr as u32).to_string(),
+            Family::V6 => Ipv6Addr::from(addr).to_string(),
+            Family::Mac48 | Family::Eui64 => self.split(addr)
+                .iter()
+                .map(|octet| format!("{:02x}", octet))
+                .collect::<Vec<_>>()
+                .join(":"),
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<(Family, u128), String> {
+    match IpAddr::from_str(addr.trim()) {
+        Ok(IpAddr::V4(addr)) => Ok((Family::V4, u32::from(addr) as u128)),
+        Ok(IpAddr::V6(addr)) => Ok((Family::V6, u128::from(addr))),
+        Err(_) => Err(format!("Invalid address {}", addr)),
+    }
+}
+
+fn ip_family(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+// 2つのアドレスを同じファミリーにそろえる。
+// IPv4-mapped IPv6 アドレス (::ffff:a.b.c.d) は、相手が IPv4 なら IPv4 として扱う
+fn pair_addrs((a_name, a): (&str, IpAddr), (b_name, b): (&str, IpAddr)) -> Result<(Family, u128, u128), String> {
+    let mismatch = || format!("{} is {} but {} is {}", a_name, ip_family(a), b_name, ip_family(b));
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => Ok((Family::V4, u32::from(a) as u128, u32::from(b) as u128)),
+        (IpAddr::V6(a), IpAddr::V6(b)) => Ok((Family::V6, u128::from(a), u128::from(b))),
+        (IpAddr::V4(a), IpAddr::V6(b)) => {
+            let b = b.to_ipv4_mapped().ok_or_else(mismatch)?;
+            Ok((Family::V4, u32::from(a) as u128, u32::from(b) as u128))
+        },
+        (IpAddr::V6(a), IpAddr::V4(b)) => {
+            let a = a.to_ipv4_mapped().ok_or_else(mismatch)?;
+            Ok((Family::V4, u32::from(a) as u128, u32::from(b) as u128))
+        },
+    }
+}
+
+// IPv4-mapped で渡されたアドレスは、結果も同じ書き方で返す
+fn ip_like(family: Family, addr: u128, like: IpAddr) -> IpAddr {
+    match (family, like) {
+        (Family::V4, IpAddr::V6(_)) => IpAddr::V6(Ipv4Addr::from(addr as u32).to_ipv6_mapped()),
+        (Family::V4, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::from(addr as u32)),
+        _ => IpAddr::V6(Ipv6Addr::from(addr)),
+    }
+}
+
+fn format_like(family: Family, addr: u128, like: IpAddr) -> String {
+    ip_like(family, addr, like).to_string()
+}
+
+// `10.0.0.0/24` / `10.0.0.1-10.0.0.9` / 単一アドレス
+#[derive(Debug, Clone, Copy)]
+struct AddrRange {
+    family: Family,
+    lo: u128,
+    hi: u128,
+}
+
+impl FromStr for AddrRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((addr, prefix)) = s.split_once('/') {
+            let (family, addr) = parse_addr(addr)?;
+            let prefix: u32 = prefix.trim().parse()
+                .ok()
+                .filter(|&prefix| prefix <= family.bits())
+                .ok_or(format!("Invalid prefix length {}", prefix))?;
+            let host_bits = family.bits() - prefix;
+            let host_mask = if host_bits == 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+            let lo = addr & !host_mask;
+            return Ok(Self { family, lo, hi: lo | host_mask });
+        }
+        if let Some((lo, hi)) = s.split_once('-') {
+            let (family, lo) = parse_addr(lo)?;
+            let (hi_family, hi) = parse_addr(hi)?;
+            if family != hi_family {
+                return Err("Range ends must be the same address family".to_string());
+            }
+            if lo > hi {
+                return Err("Range start must not be after its end".to_string());
+            }
+            return Ok(Self { family, lo, hi });
+        }
+        let (family, addr) = parse_addr(s)?;
+        Ok(Self { family, lo: addr, hi: addr })
+    }
+}
+
+impl fmt::Display for AddrRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.lo == self.hi {
+            write!(f, "{}", self.family.format(self.lo))
+        } else {
+            write!(f, "{}-{}", self.family.format(self.lo), self.family.format(self.hi))
+        }
+    }
+}
+
+impl AddrRange {
+    // 1つのCIDRで表せるならそれを返す
+    fn cidr(&self) -> Option<String> {
+        let size = (self.hi - self.lo).checked_add(1);
+        let host_bits = match size {
+            Some(size) if size.is_power_of_two() => size.trailing_zeros(),
+            Some(_) => return None,
+            None => 128,
+        };
+        let aligned = host_bits == 128 || self.lo & ((1u128 << host_bits) - 1) == 0;
+        aligned.then(|| format!("{}/{}", self.family.format(self.lo), self.family.bits() - host_bits))
+    }
+
+    // 範囲を「上位桁が固定・1桁だけ区間・残りの桁は全域」の箱に分ける
+    fn boxes(&self) -> Vec<Vec<(u32, u32)>> {
+        fn divide(prefix: &mut Vec<(u32, u32)>, lo: &[u32], hi: &[u32], max: u32, boxes: &mut Vec<Vec<(u32, u32)>>) {
+            let Some((&lo_head, lo_tail)) = lo.split_first() else {
+                boxes.push(prefix.clone());
+                return;
+            };
+            let (&hi_head, hi_tail) = hi.split_first().unwrap();
+
+            if lo_head == hi_head {
+                prefix.push((lo_head, lo_head));
+                divide(prefix, lo_tail, hi_tail, max, boxes);
+                prefix.pop();
+                return;
+            }
+
+            let lo_full = lo_tail.iter().all(|&digit| digit == 0);
+            let hi_full = hi_tail.iter().all(|&digit| digit == max);
+            if !lo_full {
+                prefix.push((lo_head, lo_head));
+                divide(prefix, lo_tail, &vec![max; hi_tail.len()], max, boxes);
+                prefix.pop();
+            }
+            let first = if lo_full { lo_head } else { lo_head + 1 };
+            let last = if hi_full { hi_head } else { hi_head - 1 };
+            if first <= last {
+                let mut full = prefix.clone();
+                full.push((first, last));
+                full.resize(full.len() + lo_tail.len(), (0, max));
+                boxes.push(full);
+            }
+            if !hi_full {
+                prefix.push((hi_head, hi_head));
+                divide(prefix, &vec![0; lo_tail.len()], hi_tail, max, boxes);
+                prefix.pop();
+            }
+        }
+
+        let mut boxes = Vec::new();
+        divide(&mut Vec::new(), &self.family.split(self.lo), &self.family.split(self.hi), self.family.digit_max(), &mut boxes);
+        boxes
+    }
+}
+
+// 桁の区間 [lo, hi] を key で加算(mod 2^bits)した像
+fn add_digit_range(lo: u32, hi: u32, key: u32, max: u32) -> Vec<(u32, u32)> {
+    if lo == 0 && hi == max {
+        return vec![(0, max)];
+    }
+    let (start, end) = ((lo + key) & max, (hi + key) & max);
+    if start <= end {
+        vec![(start, end)]
+    } else {
+        vec![(start, max), (0, end)]
+    }
+}
 
-    Ipv4Addr::new(to.octets()[0].overflowing_sub(from.octets()[0]).0,
-                    to.octets()[1].overflowing_sub(from.octets()[1]).0,
-                    to.octets()[2].overflowing_sub(from.octets()[2]).0,
-                    to.octets()[3].overflowing_sub(from.octets()[3]).0).to_string()
-                  .to_string()
+// 桁の区間 [lo, hi] を key でXORした像(2のべきに揃ったブロックごとに移す)
+fn xor_digit_range(lo: u32, hi: u32, key: u32) -> Vec<(u32, u32)> {
+    let mut images = Vec::new();
+    let mut start = lo as u64;
+    while start <= hi as u64 {
+        let mut size = if start == 0 { 1u64 << 32 } else { 1u64 << start.trailing_zeros() };
+        while start + size - 1 > hi as u64 {
+            size >>= 1;
+        }
+        let base = (start as u32 ^ key) & !((size - 1) as u32);
+        images.push((base, base + (size - 1) as u32));
+        start += size;
+    }
+    images
 }
 
-// task 3
+// 桁ごとに独立した変換なら、範囲を箱に分けて桁ごとの像の直積を取ればよい
+fn digitwise_image(range: &AddrRange, key: u128, digit_image: impl Fn(u32, u32, u32) -> Vec<(u32, u32)>) -> Vec<(u128, u128)> {
+    let family = range.family;
+    let key = family.split(key);
+
+    let mut ranges = Vec::new();
+    for digits in range.boxes() {
+        // 箱の中で区間が複数に割れるのは高々1桁なので、直積で十分小さい
+        let mut images: Vec<(Vec<u32>, Vec<u32>)> = vec![(Vec::new(), Vec::new())];
+        for (i, &(lo, hi)) in digits.iter().enumerate() {
+            let parts = digit_image(lo, hi, key[i]);
+            images = images.into_iter()
+                .flat_map(|(los, his)| parts.iter().map(move |&(lo, hi)| {
+                    let (mut los, mut his) = (los.clone(), his.clone());
+                    los.push(lo);
+                    his.push(hi);
+                    (los, his)
+                }))
+                .collect();
+        }
+        ranges.extend(images.into_iter().map(|(los, his)| (family.join(&los), family.join(&his))));
+    }
+    ranges
+}
+
+fn digitwise(family: Family, a: u128, b: u128, op: impl Fn(u32, u32) -> u32) -> u128 {
+    let digits: Vec<u32> = family.split(a).into_iter()
+        .zip(family.split(b))
+        .map(|(a, b)| op(a, b))
+        .collect();
+    family.join(&digits)
+}
+
+// アドレスの暗号方式
+trait Cipher {
+    fn encrypt(&self, family: Family, from: u128, key: u128) -> u128;
+
+    fn decrypt(&self, family: Family, to: u128, key: u128) -> u128;
+
+    // from と to から key を求める
+    fn recover_key(&self, family: Family, from: u128, to: u128) -> Result<u128, String>;
+
+    // 範囲の像。重なりや隣接はまとめなくてよい
+    fn image(&self, range: &AddrRange, key: u128, decrypt: bool) -> Result<Vec<(u128, u128)>, String>;
+}
+
+// 桁ごとの加算 (IPv4 の既定)
+struct DigitAdd;
+
+impl Cipher for DigitAdd {
+    fn encrypt(&self, family: Family, from: u128, key: u128) -> u128 {
+        let max = family.digit_max();
+        digitwise(family, from, key, |from, key| (from + key) & max)
+    }
+
+    fn decrypt(&self, family: Family, to: u128, key: u128) -> u128 {
+        let max = family.digit_max();
+        digitwise(family, to, key, |to, key| (to + max + 1 - key) & max)
+    }
+
+    fn recover_key(&self, family: Family, from: u128, to: u128) -> Result<u128, String> {
+        Ok(self.decrypt(family, to, from))
+    }
+
+    fn image(&self, range: &AddrRange, key: u128, decrypt: bool) -> Result<Vec<(u128, u128)>, String> {
+        let max = range.family.digit_max();
+        Ok(digitwise_image(range, key, |lo, hi, key| {
+            add_digit_range(lo, hi, if decrypt { (max + 1 - key) & max } else { key }, max)
+        }))
+    }
+}
+
+// 桁ごとのXOR (IPv6 の既定)
+struct DigitXor;
+
+impl Cipher for DigitXor {
+    fn encrypt(&self, family: Family, from: u128, key: u128) -> u128 {
+        digitwise(family, from, key, |from, key| from ^ key)
+    }
+
+    fn decrypt(&self, family: Family, to: u128, key: u128) -> u128 {
+        self.encrypt(family, to, key)
+    }
+
+    fn recover_key(&self, family: Family, from: u128, to: u128) -> Result<u128, String> {
+        Ok(self.encrypt(family, from, to))
+    }
+
+    fn image(&self, range: &AddrRange, key: u128, _decrypt: bool) -> Result<Vec<(u128, u128)>, String> {
+        Ok(digitwise_image(range, key, xor_digit_range))
+    }
+}
+
+// アドレス全体を1つの整数として加算する (IPv6 なら128bit)
+struct WrappingAdd;
+
+impl Cipher for WrappingAdd {
+    fn encrypt(&self, family: Family, from: u128, key: u128) -> u128 {
+        from.wrapping_add(key) & family.mask()
+    }
+
+    fn decrypt(&self, family: Family, to: u128, key: u128) -> u128 {
+        to.wrapping_sub(key) & family.mask()
+    }
+
+    fn recover_key(&self, family: Family, from: u128, to: u128) -> Result<u128, String> {
+        Ok(self.decrypt(family, to, from))
+    }
+
+    fn image(&self, range: &AddrRange, key: u128, decrypt: bool) -> Result<Vec<(u128, u128)>, String> {
+        let family = range.family;
+        if range.lo == 0 && range.hi == family.mask() {
+            return Ok(vec![(0, family.mask())]);
+        }
+        let (start, end) = if decrypt {
+            (self.decrypt(family, range.lo, key), self.decrypt(family, range.hi, key))
+        } else {
+            (self.encrypt(family, range.lo, key), self.encrypt(family, range.hi, key))
+        };
+        if start <= end {
+            Ok(vec![(start, end)])
+        } else {
+            Ok(vec![(start, family.mask()), (0, end)])
+        }
+    }
+}
+
+// key で決まる Feistel ネットワークによる置換。アドレスの形式はそのまま
+struct Permutation;
+
+const PERMUTATION_ROUNDS: u8 = 8;
+// 置換の像は構造を持たないので、1つずつ数えられる大きさまで
+const MAX_PERMUTATION_RANGE: u128 = 1 << 16;
+
+impl Permutation {
+    fn round(family: Family, key: u128, round: u8, half: u128) -> u128 {
+        let mut hasher = Sha1::new();
+        hasher.update(key.to_be_bytes());
+        hasher.update([round]);
+        hasher.update(half.to_be_bytes());
+        let digest = hasher.finalize();
+        u128::from_be_bytes(digest[..16].try_into().unwrap()) & Self::half_mask(family)
+    }
+
+    fn half_bits(family: Family) -> u32 {
+        family.bits() / 2
+    }
+
+    fn half_mask(family: Family) -> u128 {
+        (1 << Self::half_bits(family)) - 1
+    }
+}
+
+impl Cipher for Permutation {
+    fn encrypt(&self, family: Family, from: u128, key: u128) -> u128 {
+        let (mut left, mut right) = (from >> Self::half_bits(family), from & Self::half_mask(family));
+        for round in 0..PERMUTATION_ROUNDS {
+            (left, right) = (right, left ^ Self::round(family, key, round, right));
+        }
+        (left << Self::half_bits(family)) | right
+    }
+
+    fn decrypt(&self, family: Family, to: u128, key: u128) -> u128 {
+        let (mut left, mut right) = (to >> Self::half_bits(family), to & Self::half_mask(family));
+        for round in (0..PERMUTATION_ROUNDS).rev() {
+            (left, right) = (right ^ Self::round(family, key, round, left), left);
+        }
+        (left << Self::half_bits(family)) | right
+    }
+
+    fn recover_key(&self, _family: Family, _from: u128, _to: u128) -> Result<u128, String> {
+        Err("The permute scheme cannot recover a key from addresses; pass to and key to find from".to_string())
+    }
+
+    fn image(&self, range: &AddrRange, key: u128, decrypt: bool) -> Result<Vec<(u128, u128)>, String> {
+        if range.hi - range.lo >= MAX_PERMUTATION_RANGE {
+            return Err(format!("The permute scheme supports ranges of up to {} addresses", MAX_PERMUTATION_RANGE));
+        }
+        Ok((range.lo..=range.hi)
+            .map(|addr| if decrypt { self.decrypt(range.family, addr, key) } else { self.encrypt(range.family, addr, key) })
+            .map(|addr| (addr, addr))
+            .collect())
+    }
+}
+
+// ?scheme= で選ぶ。未指定なら既存のルートと同じく IPv4 は add、IPv6 は xor
+//
+// key ルートは from と to から key を、to と key から from (decrypt) を求める。
+// permute は key を求められないので後者のみ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheme {
+    Add,
+    Xor,
+    WrappingAdd,
+    Permute,
+}
+
+impl Scheme {
+    fn cipher(self) -> &'static dyn Cipher {
+        match self {
+            Scheme::Add => &DigitAdd,
+            Scheme::Xor => &DigitXor,
+            Scheme::WrappingAdd => &WrappingAdd,
+            Scheme::Permute => &Permutation,
+        }
+    }
+
+    fn cipher_for(scheme: Option<Scheme>, family: Family) -> &'static dyn Cipher {
+        scheme.unwrap_or(match family {
+            Family::V6 => Scheme::Xor,
+            _ => Scheme::Add,
+        }).cipher()
+    }
+}
+
+// 範囲全体の像を、連続する範囲の並びにまとめて返す
+fn range_image(cipher: &dyn Cipher, range: &AddrRange, key: u128, decrypt: bool) -> Result<Vec<AddrRange>, String> {
+    let family = range.family;
+    let mut ranges = cipher.image(range, key, decrypt)?;
+
+    ranges.sort();
+    let mut merged: Vec<(u128, u128)> = Vec::new();
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if last.1 == u128::MAX || lo <= last.1 + 1 => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    Ok(merged.into_iter().map(|(lo, hi)| AddrRange { family, lo, hi }).collect())
+}
+
+#[derive(Serialize)]
+pub struct BlockResult {
+    ranges: Vec<String>,
+    // 結果が1つの連続した範囲に収まっているか
+    contiguous: bool,
+    cidr: Option<String>,
+}
+
+fn calc_block(range: &str, key: &str, scheme: Option<Scheme>, decrypt: bool) -> Result<Json<BlockResult>, (StatusCode, String)> {
+    let range = AddrRange::from_str(range).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (key_family, key) = parse_addr(key).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if key_family != range.family {
+        return Err((StatusCode::BAD_REQUEST, "Address and key must be the same address family".to_string()));
+    }
+
+    let cipher = Scheme::cipher_for(scheme, range.family);
+    let image = range_image(cipher, &range, key, decrypt).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(BlockResult {
+        contiguous: image.len() == 1,
+        cidr: match image.as_slice() {
+            [range] => range.cidr(),
+            _ => None,
+        },
+        ranges: image.iter().map(AddrRange::to_string).collect(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct FromKeyBlockQuery {
+    from: String,
+    key: String,
+    scheme: Option<Scheme>,
+}
+
+pub async fn from_key_calc_block(query: Query<FromKeyBlockQuery>) -> Result<Json<BlockResult>, (StatusCode, String)> {
+    calc_block(&query.from, &query.key, query.scheme, false)
+}
+
+#[derive(Deserialize)]
+pub struct ToKeyBlockQuery {
+    to: String,
+    key: String,
+    scheme: Option<Scheme>,
+}
+
+// 暗号化された範囲と key から元の範囲を求める
+pub async fn to_key_calc_block(query: Query<ToKeyBlockQuery>) -> Result<Json<BlockResult>, (StatusCode, String)> {
+    calc_block(&query.to, &query.key, query.scheme, true)
+}
+
+// まとめて変換する
+//
+// JSON配列か、1行1エントリのNDJSON (Content-Type: application/x-ndjson) で受け取り、
+// 同じ形式で順番どおりに返す。エントリの scheme が無ければ ?scheme= を使う
+//
+// from, key, to のうち2つを渡すと残りの1つを求める
+#[derive(Deserialize)]
+struct BatchEntry {
+    from: Option<String>,
+    key: Option<String>,
+    to: Option<String>,
+    scheme: Option<Scheme>,
+}
+
+#[derive(Deserialize)]
+pub struct SchemeQuery {
+    scheme: Option<Scheme>,
+}
+
+#[derive(Serialize, Default)]
+pub struct BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn calc_entry(entry: BatchEntry, scheme: Option<Scheme>) -> Result<BatchResult, String> {
+    let parse = |addr: &str| IpAddr::from_str(addr.trim()).map_err(|_| format!("Invalid address {}", addr));
+    let cipher_for = |family| Scheme::cipher_for(entry.scheme.or(scheme), family);
+    match (&entry.from, &entry.key, &entry.to) {
+        (Some(from), Some(key), None) => {
+            let from_addr = parse(from)?;
+            let (family, from, key) = pair_addrs(("from", from_addr), ("key", parse(key)?))?;
+            let dest = format_like(family, cipher_for(family).encrypt(family, from, key), from_addr);
+            Ok(BatchResult { dest: Some(dest), ..Default::default() })
+        },
+        (Some(from), None, Some(to)) => {
+            let (family, from, to) = pair_addrs(("from", parse(from)?), ("to", parse(to)?))?;
+            let key = family.format(cipher_for(family).recover_key(family, from, to)?);
+            Ok(BatchResult { key: Some(key), ..Default::default() })
+        },
+        (None, Some(key), Some(to)) => {
+            let to_addr = parse(to)?;
+            let (family, to, key) = pair_addrs(("to", to_addr), ("key", parse(key)?))?;
+            let source = format_like(family, cipher_for(family).decrypt(family, to, key), to_addr);
+            Ok(BatchResult { source: Some(source), ..Default::default() })
+        },
+        _ => Err("Exactly two of from, key and to are required".to_string()),
+    }
+}
+
+fn calc_value(value: Value, scheme: Option<Scheme>) -> BatchResult {
+    serde_json::from_value(value)
+        .map_err(|e| format!("Invalid entry: {}", e))
+        .and_then(|entry| calc_entry(entry, scheme))
+        .unwrap_or_else(|error| BatchResult { error: Some(error), ..Default::default() })
+}
+
+pub async fn batch_calc(Query(query): Query<SchemeQuery>, headers: HeaderMap, body: Bytes) -> Response {
+    let ndjson = headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("ndjson") || content_type.contains("jsonlines"));
+
+    if ndjson {
+        let Ok(body) = std::str::from_utf8(&body) else {
+            return (StatusCode::BAD_REQUEST, "Body is not UTF-8".to_string()).into_response();
+        };
+        let lines: Vec<String> = body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match serde_json::from_str(line) {
+                Ok(value) => calc_value(value, query.scheme),
+                Err(e) => BatchResult { error: Some(format!("Invalid JSON: {}", e)), ..Default::default() },
+            })
+            .map(|result| serde_json::to_string(&result).unwrap())
+            .collect();
+        return (
+            [(CONTENT_TYPE, "application/x-ndjson")],
+            lines.into_iter().map(|line| line + "\n").collect::<String>(),
+        ).into_response();
+    }
+
+    match serde_json::from_slice::<Vec<Value>>(&body) {
+        Ok(entries) => Json(entries.into_iter().map(|entry| calc_value(entry, query.scheme)).collect::<Vec<_>>()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Expected a JSON array: {}", e)).into_response(),
+    }
+}
+
+// MAC アドレス (MAC-48 / EUI-64)
+//
+// `aa:bb:cc:dd:ee:ff` または `aa-bb-cc-dd-ee-ff`。既定の方式は IPv4 と同じ桁ごとの加算
+fn parse_mac(mac: &str) -> Result<(Family, u128), String> {
+    let invalid = || format!("Invalid MAC address {}", mac);
+    // 区切りは : か - のどちらか一方に揃っていなければならない
+    let mac = mac.trim();
+    let separator = mac.chars().find(|c| *c == ':' || *c == '-').ok_or_else(invalid)?;
+    let octets = mac
+        .split(separator)
+        .map(|octet| if octet.len() == 2 && octet.bytes().all(|b| b.is_ascii_hexdigit()) {
+            u32::from_str_radix(octet, 16).map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let family = match octets.len() {
+        6 => Family::Mac48,
+        8 => Family::Eui64,
+        _ => return Err(invalid()),
+    };
+    Ok((family, family.join(&octets)))
+}
+
+fn pair_macs((a_name, a): (&str, &str), (b_name, b): (&str, &str)) -> Result<(Family, u128, u128), (StatusCode, String)> {
+    let (a_family, a) = parse_mac(a).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (b_family, b) = parse_mac(b).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if a_family != b_family {
+        let name = |family| if family == Family::Mac48 { "MAC-48" } else { "EUI-64" };
+        return Err((StatusCode::BAD_REQUEST, format!("{} is {} but {} is {}", a_name, name(a_family), b_name, name(b_family))));
+    }
+    Ok((a_family, a, b))
+}
+
+#[derive(Deserialize)]
+pub struct MacKeyQuery {
+    from: String,
+    key: String,
+    scheme: Option<Scheme>,
+}
+
+pub async fn from_key_calc_mac(query: Query<MacKeyQuery>) -> Result<String, (StatusCode, String)> {
+    let (family, from, key) = pair_macs(("from", &query.from), ("key", &query.key))?;
+    let cipher = Scheme::cipher_for(query.scheme, family);
+
+    Ok(family.format(cipher.encrypt(family, from, key)))
+}
+
+#[derive(Deserialize)]
+pub struct MacToQuery {
+    from: Option<String>,
+    to: String,
+    key: Option<String>,
+    scheme: Option<Scheme>,
+}
+
+pub async fn from_to_calc_mac(query: Query<MacToQuery>) -> Result<String, (StatusCode, String)> {
+    match (&query.from, &query.key) {
+        (Some(from), None) => {
+            let (family, from, to) = pair_macs(("from", from), ("to", &query.to))?;
+            Scheme::cipher_for(query.scheme, family)
+                .recover_key(family, from, to)
+                .map(|key| family.format(key))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))
+        },
+        (None, Some(key)) => {
+            let (family, to, key) = pair_macs(("to", &query.to), ("key", key))?;
+            Ok(family.format(Scheme::cipher_for(query.scheme, family).decrypt(family, to, key)))
+        },
+        _ => Err((StatusCode::BAD_REQUEST, ONE_OF_FROM_OR_KEY.to_string())),
+    }
+}
+
+// ソケットアドレス (`ip:port` / `[ip]:port`)
+//
+// アドレス部分は /2/dest と同じ変換、ポートは方式によらず mod 65536 で加算する
 #[derive(Deserialize)]
-pub struct FromKeyQueryV6 {
-    from: Ipv6Addr,
-    key: Ipv6Addr,
+pub struct SocketKeyQuery {
+    from: SocketAddr,
+    key: SocketAddr,
+    scheme: Option<Scheme>,
 }
 
-pub async fn from_key_calc_v6(from_key_query: Query<FromKeyQueryV6>) -> String {
-    let from = from_key_query.from;
-    let key = from_key_query.key;
+pub async fn from_key_calc_socket(query: Query<SocketKeyQuery>) -> Result<String, (StatusCode, String)> {
+    let (family, from, key) = pair_addrs(("from", query.from.ip()), ("key", query.key.ip()))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let cipher = Scheme::cipher_for(query.scheme, family);
 
-    Ipv6Addr::new(
-        from.segments()[0] ^ key.segments()[0],
-        from.segments()[1] ^ key.segments()[1],
-        from.segments()[2] ^ key.segments()[2],
-        from.segments()[3] ^ key.segments()[3],
-        from.segments()[4] ^ key.segments()[4],
-        from.segments()[5] ^ key.segments()[5],
-        from.segments()[6] ^ key.segments()[6],
-        from.segments()[7] ^ key.segments()[7]
-    ).to_string()
+    let ip = ip_like(family, cipher.encrypt(family, from, key), query.from.ip());
+    let port = query.from.port().wrapping_add(query.key.port());
+    Ok(SocketAddr::new(ip, port).to_string())
 }
 
 #[derive(Deserialize)]
-pub struct FromToQueryV6 {
-    from: Ipv6Addr,
-    to: Ipv6Addr,
-}
-
-pub async fn from_to_calc_v6(from_to_query: Query<FromToQueryV6>) -> String {
-    let from = from_to_query.from;
-    let to = from_to_query.to;
-
-    Ipv6Addr::new(
-        to.segments()[0] ^ from.segments()[0],
-        to.segments()[1] ^ from.segments()[1],
-        to.segments()[2] ^ from.segments()[2],
-        to.segments()[3] ^ from.segments()[3],
-        to.segments()[4] ^ from.segments()[4],
-        to.segments()[5] ^ from.segments()[5],
-        to.segments()[6] ^ from.segments()[6],
-        to.segments()[7] ^ from.segments()[7]
-    ).to_string()
-}
\ No newline at end of file
+pub struct SocketToQuery {
+    from: Option<SocketAddr>,
+    to: SocketAddr,
+    key: Option<SocketAddr>,
+    scheme: Option<Scheme>,
+}
+
+pub async fn from_to_calc_socket(query: Query<SocketToQuery>) -> Result<String, (StatusCode, String)> {
+    let to_addr = query.to;
+    match (query.from, query.key) {
+        (Some(from_addr), None) => {
+            let (family, from, to) = pair_addrs(("from", from_addr.ip()), ("to", to_addr.ip()))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            let cipher = Scheme::cipher_for(query.scheme, family);
+
+            let key = cipher.recover_key(family, from, to).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            let ip = match family {
+                Family::V4 => IpAddr::V4(Ipv4Addr::from(key as u32)),
+                _ => IpAddr::V6(Ipv6Addr::from(key)),
+            };
+            let port = to_addr.port().wrapping_sub(from_addr.port());
+            Ok(SocketAddr::new(ip, port).to_string())
+        },
+        (None, Some(key_addr)) => {
+            let (family, to, key) = pair_addrs(("to", to_addr.ip()), ("key", key_addr.ip()))
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            let cipher = Scheme::cipher_for(query.scheme, family);
+
+            let ip = ip_like(family, cipher.decrypt(family, to, key), to_addr.ip());
+            let port = to_addr.port().wrapping_sub(key_addr.port());
+            Ok(SocketAddr::new(ip, port).to_string())
+        },
+        _ => Err((StatusCode::BAD_REQUEST, ONE_OF_FROM_OR_KEY.to_string())),
+    }
+}
+
+// 送信元と観測された宛先の組 (sources[i] -> destinations[i]) から、
+// 全ての組に合う key を求める。合わないときは多数派の key と食い違う組を返す
+#[derive(Deserialize)]
+pub struct SolveRequest {
+    sources: Vec<IpAddr>,
+    destinations: Vec<IpAddr>,
+}
+
+#[derive(Serialize)]
+pub struct KeyConflict {
+    index: usize,
+    from: String,
+    to: String,
+    // この組だけから求めた key
+    key: String,
+}
+
+#[derive(Serialize)]
+pub struct KeySolution {
+    key: String,
+    // key が全ての組に合うか
+    consistent: bool,
+    // key に合う組の数
+    supporting: usize,
+    conflicts: Vec<KeyConflict>,
+}
+
+pub async fn solve_key(
+    Query(query): Query<SchemeQuery>,
+    Json(request): Json<SolveRequest>,
+) -> Result<Json<KeySolution>, (StatusCode, String)> {
+    if request.sources.is_empty() || request.sources.len() != request.destinations.len() {
+        return Err((StatusCode::BAD_REQUEST, "sources and destinations must be non-empty and the same length".to_string()));
+    }
+
+    let mut keys: Vec<(Family, u128)> = Vec::new();
+    for (i, (&from, &to)) in request.sources.iter().zip(&request.destinations).enumerate() {
+        let (family, from, to) = pair_addrs(("from", from), ("to", to))
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Pair {}: {}", i, e)))?;
+        let key = Scheme::cipher_for(query.scheme, family)
+            .recover_key(family, from, to)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        keys.push((family, key));
+    }
+
+    // 最も多くの組が支持する key を選ぶ(同数なら先に現れた方)
+    let mut counts: HashMap<(Family, u128), usize> = HashMap::new();
+    for key in &keys {
+        *counts.entry(*key).or_default() += 1;
+    }
+    let best = *keys.iter()
+        .rev()
+        .max_by_key(|key| counts[key])
+        .unwrap();
+
+    let conflicts: Vec<KeyConflict> = keys.iter()
+        .enumerate()
+        .filter(|(_, key)| **key != best)
+        .map(|(i, &(family, key))| KeyConflict {
+            index: i,
+            from: request.sources[i].to_string(),
+            to: request.destinations[i].to_string(),
+            key: family.format(key),
+        })
+        .collect();
+
+    Ok(Json(KeySolution {
+        key: best.0.format(best.1),
+        consistent: conflicts.is_empty(),
+        supporting: counts[&best],
+        conflicts,
+    }))
+}
@@ -1,15 +1,288 @@
-use axum::{http::HeaderMap, http::StatusCode, response::IntoResponse, body::{Body, to_bytes}};
-use serde::Deserialize;
+use axum::{extract::{multipart::MultipartError, DefaultBodyLimit, FromRequest, Json, Multipart, Query, Request, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}, body::Body};
+use serde::{Deserialize, Serialize};
 use toml;
 use cargo_manifest::Manifest;
+use http_body_util::BodyExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, LazyLock};
+use uuid::Uuid;
 use serde_yml;
 use serde_json;
 
+const DEFAULT_CAMPAIGN: &str = "christmas-2024";
+const MAGIC_KEYWORD: &str = "Christmas 2024";
+const EDITIONS: [&str; 4] = ["2015", "2018", "2021", "2024"];
 
-#[derive(Deserialize, Debug)]
+const DEFAULT_MAX_MANIFEST_SIZE: usize = 1024 * 1024;
+
+// 環境変数 MANIFEST_MAX_SIZE (バイト数、1以上) で上書きできる
+static MAX_MANIFEST_SIZE: LazyLock<Result<usize, String>> = LazyLock::new(|| {
+    let Ok(size) = std::env::var("MANIFEST_MAX_SIZE") else {
+        return Ok(DEFAULT_MAX_MANIFEST_SIZE);
+    };
+    size.trim()
+        .parse()
+        .ok()
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("MANIFEST_MAX_SIZE must be a positive number of bytes, got {}", size))
+});
+
+fn max_manifest_size() -> usize {
+    *MAX_MANIFEST_SIZE.as_ref().expect("MANIFEST_MAX_SIZE is checked by init")
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum KeywordMatch {
+    #[default]
+    All,
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FieldType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl FieldType {
+    fn matches(&self, value: &toml::Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::String, toml::Value::String(_))
+                | (Self::Integer, toml::Value::Integer(_))
+                | (Self::Number, toml::Value::Integer(_) | toml::Value::Float(_))
+                | (Self::Boolean, toml::Value::Boolean(_))
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::String => "a string",
+            Self::Integer => "an integer",
+            Self::Number => "a number",
+            Self::Boolean => "a boolean",
+        }
+    }
+}
+
+// JSON Schemaのプロパティ定義に相当する
+#[derive(Debug, Clone, Deserialize)]
+struct FieldSchema {
+    #[serde(rename = "type")]
+    field_type: FieldType,
+    #[serde(default)]
+    required: bool,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    max_length: Option<usize>,
+}
+
+impl FieldSchema {
+    fn new(field_type: FieldType, required: bool) -> Self {
+        Self { field_type, required, minimum: None, maximum: None, max_length: None }
+    }
+
+    fn check(&self, field: &str, value: Option<&toml::Value>) -> Result<(), String> {
+        let value = match value {
+            Some(value) => value,
+            None if self.required => return Err(format!("{} is missing", field)),
+            None => return Ok(()),
+        };
+        if !self.field_type.matches(value) {
+            return Err(format!("{} is not {}", field, self.field_type.name()));
+        }
+
+        let number = match value {
+            toml::Value::Integer(number) => Some(*number as f64),
+            toml::Value::Float(number) => Some(*number),
+            _ => None,
+        };
+        if let Some(number) = number {
+            if self.minimum.is_some_and(|minimum| number < minimum) {
+                return Err(format!("{} is less than {}", field, self.minimum.unwrap_or_default()));
+            }
+            if self.maximum.is_some_and(|maximum| number > maximum) {
+                return Err(format!("{} is greater than {}", field, self.maximum.unwrap_or_default()));
+            }
+        }
+        if let (Some(text), Some(max_length)) = (value.as_str(), self.max_length) {
+            if text.chars().count() > max_length {
+                return Err(format!("{} is longer than {} characters", field, max_length));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_orders_key() -> String {
+    "orders".to_string()
+}
+
+fn default_order_schema() -> BTreeMap<String, FieldSchema> {
+    let mut quantity = FieldSchema::new(FieldType::Integer, true);
+    quantity.minimum = Some(0.0);
+    quantity.maximum = Some(u32::MAX as f64);
+    BTreeMap::from([
+        ("item".to_string(), FieldSchema::new(FieldType::String, true)),
+        ("quantity".to_string(), quantity),
+    ])
+}
+
+// キャンペーンごとの検証ルール
+//
+// [campaigns.easter-2025]
+// keywords = ["Easter 2025", "Spring"]
+// keyword_match = "any"
+// orders_key = "baskets"
+// order.price = { type = "number", minimum = 0 }
+// order.notes = { type = "string", max_length = 200 }
+#[derive(Debug, Clone, Deserialize)]
+struct CampaignRules {
+    keywords: Vec<String>,
+    #[serde(default)]
+    keyword_match: KeywordMatch,
+    #[serde(default = "default_orders_key")]
+    orders_key: String,
+    // item と quantity は常に検証され、ここでは追加の項目を定義する
+    #[serde(default)]
+    order: BTreeMap<String, FieldSchema>,
+    // item と quantity を含む全ての項目。読み込み時に一度だけ作る
+    #[serde(skip)]
+    schema: BTreeMap<String, FieldSchema>,
+}
+
+impl CampaignRules {
+    fn has_keywords(&self, keywords: &[String]) -> bool {
+        let contains = |keyword: &String| keywords.contains(keyword);
+        match self.keyword_match {
+            KeywordMatch::All => self.keywords.iter().all(contains),
+            KeywordMatch::Any => self.keywords.iter().any(contains),
+        }
+    }
+
+    // 組み込みの item と quantity の制約は上書きさせない
+    fn build_schema(&mut self) -> Result<(), String> {
+        let mut schema = default_order_schema();
+        if let Some(field) = self.order.keys().find(|field| schema.contains_key(field.as_str())) {
+            return Err(format!("order.{} cannot be redefined", field));
+        }
+        schema.extend(self.order.clone());
+        self.schema = schema;
+        Ok(())
+    }
+
+    fn parse_order(&self, order: &toml::Value) -> Result<Order, String> {
+        let table = order.as_table().ok_or("order is not a table")?;
+        let schema = &self.schema;
+        for (field, field_schema) in schema {
+            field_schema.check(field, table.get(field))?;
+        }
+
+        let item = table.get("item").and_then(|item| item.as_str()).ok_or("item is not a string")?.to_string();
+        let quantity = table.get("quantity")
+            .and_then(|quantity| quantity.as_integer())
+            .and_then(|quantity| u32::try_from(quantity).ok())
+            .ok_or("quantity is out of range")?;
+        let extra = table.iter()
+            .filter(|(field, _)| field.as_str() != "item" && field.as_str() != "quantity" && schema.contains_key(field.as_str()))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        Ok(Order { item, quantity, extra })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestRules {
+    default: String,
+    campaigns: BTreeMap<String, CampaignRules>,
+}
+
+impl Default for ManifestRules {
+    fn default() -> Self {
+        let christmas = CampaignRules {
+            keywords: vec![MAGIC_KEYWORD.to_string()],
+            keyword_match: KeywordMatch::All,
+            orders_key: default_orders_key(),
+            order: BTreeMap::new(),
+            schema: default_order_schema(),
+        };
+        Self {
+            default: DEFAULT_CAMPAIGN.to_string(),
+            campaigns: BTreeMap::from([(DEFAULT_CAMPAIGN.to_string(), christmas)]),
+        }
+    }
+}
+
+impl ManifestRules {
+    // 環境変数 MANIFEST_RULES にTOMLのルールファイルを指定できる
+    fn from_env() -> Result<Self, String> {
+        let path = match std::env::var("MANIFEST_RULES") {
+            Ok(path) => path,
+            Err(_) => return Ok(Self::default()),
+        };
+        let rules = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read manifest rules {}: {}", path, e))?;
+        let mut rules: Self = toml::from_str(&rules).map_err(|e| format!("Invalid manifest rules {}: {}", path, e))?;
+        if !rules.campaigns.contains_key(&rules.default) {
+            return Err(format!("Default campaign {} is not defined in {}", rules.default, path));
+        }
+        for (name, campaign) in &mut rules.campaigns {
+            campaign.build_schema().map_err(|e| format!("Invalid campaign {} in {}: {}", name, path, e))?;
+        }
+        Ok(rules)
+    }
+
+    fn campaign(&self, name: Option<&str>) -> Option<&CampaignRules> {
+        self.campaigns.get(name.unwrap_or(&self.default))
+    }
+}
+
+static MANIFEST_RULES: LazyLock<Result<ManifestRules, String>> = LazyLock::new(ManifestRules::from_env);
+
+// ルールファイルとサイズ上限が読めなければ起動させない
+pub fn init() -> Result<(), String> {
+    MANIFEST_RULES.as_ref().map_err(Clone::clone)?;
+    MAX_MANIFEST_SIZE.as_ref().map_err(Clone::clone)?;
+    Ok(())
+}
+
+fn manifest_rules() -> &'static ManifestRules {
+    MANIFEST_RULES.as_ref().expect("manifest rules are checked by init")
+}
+
+#[derive(Clone)]
+pub struct StateOrders {
+    pub pool: Arc<sqlx::PgPool>,
+}
+
+pub const MAKE_DB_SQL: &str = "CREATE TABLE IF NOT EXISTS manifest_orders (
+    id UUID PRIMARY KEY,
+    package TEXT NOT NULL,
+    version TEXT,
+    item TEXT NOT NULL,
+    quantity BIGINT NOT NULL,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);";
+
+const INSERT_ORDER_SQL: &str = "INSERT INTO manifest_orders (id, package, version, item, quantity) VALUES ($1, $2, $3, $4, $5);";
+
+const SELECT_ORDERS_SQL: &str = "SELECT * FROM manifest_orders
+    WHERE ($1::TEXT IS NULL OR package = $1) AND ($2::TEXT IS NULL OR item = $2)
+    ORDER BY received_at ASC, id ASC;";
+
+const SELECT_TOTALS_SQL: &str = "SELECT item, SUM(quantity)::BIGINT AS quantity FROM manifest_orders
+    WHERE ($1::TEXT IS NULL OR package = $1) AND ($2::TEXT IS NULL OR item = $2)
+    GROUP BY item ORDER BY item ASC;";
+
+#[derive(Deserialize, Serialize, Debug)]
 struct Order {
     item: String,
     quantity: u32,
+    #[serde(flatten)]
+    extra: BTreeMap<String, toml::Value>,
 }
 
 impl std::fmt::Display for Order {
@@ -18,266 +291,1134 @@ impl std::fmt::Display for Order {
     }
 }
 
-#[axum::debug_handler]
-pub async fn return_manifest(headers: HeaderMap, body: Body) -> impl IntoResponse {
-    match headers.get("Content-Type").map(|v| v.to_str().unwrap()) {
-        Some("application/toml") => {
-            let content_size:usize = headers.get("Content-Length").expect("No Content-Length header").to_str().unwrap().parse().expect("Invalid Content-Length header");
-            let bytes = to_bytes(body, content_size).await.expect("Invalid body");
-            let toml_str = String::from_utf8(bytes.to_vec()).expect("Invalid body");
-
-            let toml = if let Ok(toml) = toml::from_str::<Manifest>(&toml_str) {
-                toml
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string())
-            };
+// 対応しているマニフェストの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ManifestFormat {
+    Toml,
+    Yaml,
+    Json,
+}
 
-            let packege = if let Some(packege) = toml.package{
-                packege
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string())
-            };
-
-            if let Some(keywords) = packege.keywords {
-                match keywords {
-                    cargo_manifest::MaybeInherited::Local(keywords) => {
-                        if !keywords.contains(&"Christmas 2024".to_string()) {
-                            return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                        }
-                    },
-                    _ => {
-                        return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                    }
-                }
-            } else {
-                return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-            }
+#[derive(Debug, Serialize)]
+struct ParseError {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
 
+impl ParseError {
+    fn from_toml(text: &str, e: toml::de::Error) -> Self {
+        let (line, column) = match e.span() {
+            Some(span) => {
+                let (line, column) = line_column(text, span.start);
+                (Some(line), Some(column))
+            },
+            None => (None, None),
+        };
+        Self { message: e.message().to_string(), line, column }
+    }
 
+    fn from_yaml(e: serde_yml::Error) -> Self {
+        let location = e.location();
+        Self {
+            message: e.to_string(),
+            line: location.as_ref().map(|l| l.line()),
+            column: location.as_ref().map(|l| l.column()),
+        }
+    }
 
-            let metadata: toml::Value = if let Some(metadata) = packege.metadata {
-                metadata
-            } else {
-                return (StatusCode::NO_CONTENT, "No metadata found".to_string())
-            };
-            
-            let order_value = if let Some(order) = metadata.get("orders") {
-                order
-            } else {
-                return (StatusCode::NO_CONTENT, "No order found".to_string())
-            };
-
-            let mut orders: Vec<Order> = Vec::new();
-            for order in order_value.as_array().unwrap() {
-                let item = if let Some(item) = order.get("item") {
-                    let item = match item {
-                        toml::Value::String(item) => item,
-                        _ => continue,
-                    };
-                    item.to_owned()
-                } else {
-                    continue;
-                };
-                let quantity:u32 = {
-                    let quantity_str = if let Some(quantity) = order.get("quantity") {
-                        quantity.to_string()
-                    } else {
-                        continue;
-                    };
-                    if let Ok(quantity) = quantity_str.parse::<u32>() {
-                        quantity
-                    } else {
-                        continue;
-                    }
-                };
-                orders.push(Order{item, quantity});
-            }
+    fn from_json(e: serde_json::Error) -> Self {
+        Self { message: e.to_string(), line: Some(e.line()), column: Some(e.column()) }
+    }
+}
 
-            if orders.len() == 0 {
-                return (StatusCode::NO_CONTENT, "No order found".to_string())
-            }
+// バイト位置を1始まりの行・列に変換
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map(|l| l.chars().count()).unwrap_or(0) + 1;
+    (line, column)
+}
 
-            let result: Vec<String> = orders.iter().map(|order| order.to_string()).collect();
+impl ManifestFormat {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "application/toml" => Some(Self::Toml),
+            "application/yaml" => Some(Self::Yaml),
+            "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    // multipartの各ファイルはContent-Typeか拡張子で判定する
+    fn from_file(content_type: Option<&str>, path: &str) -> Self {
+        if let Some(format) = content_type.and_then(Self::from_content_type) {
+            return format;
+        }
+        match path.rsplit('.').next() {
+            Some("yaml") | Some("yml") => Self::Yaml,
+            Some("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    // Acceptヘッダーに並んだ順で最初に対応できる形式を選ぶ
+    fn from_accept(accept: &str) -> Option<Self> {
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+            .find_map(Self::from_content_type)
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Toml => "application/toml",
+            Self::Yaml => "application/yaml",
+            Self::Json => "application/json",
+        }
+    }
 
-            (StatusCode::OK, result.join("\n"))
+    fn serialize(&self, manifest: &Manifest) -> Option<String> {
+        match self {
+            Self::Toml => toml::to_string(manifest).ok(),
+            Self::Yaml => serde_yml::to_string(manifest).ok(),
+            Self::Json => serde_json::to_string_pretty(manifest).ok(),
+        }
+    }
 
+    // 変換結果の確認用に、汎用的な値を経由せずManifestへ読み込む
+    fn deserialize(&self, text: &str) -> Option<Manifest> {
+        match self {
+            Self::Toml => toml::from_str(text).ok(),
+            Self::Yaml => serde_yml::from_str(text).ok(),
+            Self::Json => serde_json::from_str(text).ok(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Toml => "toml",
+            Self::Yaml => "yaml",
+            Self::Json => "json",
+        }
+    }
+
+    // 形式ごとの差分は汎用的な値への変換のみ
+    fn parse(&self, text: &str) -> Result<toml::Value, ParseError> {
+        match self {
+            Self::Toml => toml::from_str::<toml::Table>(text)
+                .map(toml::Value::Table)
+                .map_err(|e| ParseError::from_toml(text, e)),
+            Self::Yaml => serde_yml::from_str(text).map_err(ParseError::from_yaml).and_then(from_generic),
+            Self::Json => serde_json::from_str(text).map_err(ParseError::from_json).and_then(from_generic),
+        }
+    }
+}
+
+// TOMLにはnullが無いので、値がnullのキーは省略されたものとして扱う
+fn drop_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(drop_nulls);
         },
-        Some("application/yaml") => {
-            let content_size:usize = headers.get("Content-Length").expect("No Content-Length header").to_str().unwrap().parse().expect("Invalid Content-Length header");
-            let bytes = to_bytes(body, content_size).await.expect("Invalid body");
-            let yaml_str = String::from_utf8(bytes.to_vec()).expect("Invalid body");
-
-            // YAMLを直接Manifestに変換
-            let manifest: Manifest = if let Ok(manifest) = serde_yml::from_str(&yaml_str) {
-                manifest
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-            };
+        serde_json::Value::Array(values) => values.iter_mut().for_each(drop_nulls),
+        _ => {},
+    }
+}
 
-            // Cargoマニフェストの検証
-            let package = if let Some(package) = manifest.package {
-                package
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-            };
-
-            if let Some(keywords) = package.keywords {
-                match keywords {
-                    cargo_manifest::MaybeInherited::Local(keywords) => {
-                        if !keywords.contains(&"Christmas 2024".to_string()) {
-                            return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                        }
-                    },
-                    _ => return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                }
-            } else {
-                return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string());
+fn from_generic(mut value: serde_json::Value) -> Result<toml::Value, ParseError> {
+    drop_nulls(&mut value);
+    toml::Value::try_from(value).map_err(|e| ParseError {
+        message: e.to_string(),
+        line: None,
+        column: None,
+    })
+}
+
+fn to_manifest(value: toml::Value) -> Result<Manifest, ParseError> {
+    Manifest::deserialize(value).map_err(|e| ParseError {
+        message: e.message().to_string(),
+        line: None,
+        column: None,
+    })
+}
+
+#[derive(Debug)]
+enum ManifestError {
+    InvalidManifest,
+    MagicKeywordMissing,
+    NoMetadata,
+    NoOrders,
+    InvalidOrdersFormat,
+}
+
+impl IntoResponse for ManifestError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::InvalidManifest => (StatusCode::BAD_REQUEST, "Invalid manifest"),
+            Self::MagicKeywordMissing => (StatusCode::BAD_REQUEST, "Magic keyword not provided"),
+            Self::NoMetadata => (StatusCode::NO_CONTENT, "No metadata found"),
+            Self::NoOrders => (StatusCode::NO_CONTENT, "No order found"),
+            Self::InvalidOrdersFormat => (StatusCode::BAD_REQUEST, "Invalid orders format"),
+        }.into_response()
+    }
+}
+
+fn validate_keywords(manifest: &Manifest, rules: &CampaignRules) -> Result<(), ManifestError> {
+    let package = manifest.package.as_ref().ok_or(ManifestError::InvalidManifest)?;
+
+    match &package.keywords {
+        Some(cargo_manifest::MaybeInherited::Local(keywords)) if rules.has_keywords(keywords) => Ok(()),
+        _ => Err(ManifestError::MagicKeywordMissing),
+    }
+}
+
+fn order_values<'a>(manifest: &'a Manifest, rules: &CampaignRules) -> Result<&'a Vec<toml::Value>, ManifestError> {
+    let package = manifest.package.as_ref().ok_or(ManifestError::InvalidManifest)?;
+    let metadata = package.metadata.as_ref().ok_or(ManifestError::NoMetadata)?;
+    let orders = metadata.get(&rules.orders_key).ok_or(ManifestError::NoOrders)?;
+    orders.as_array().ok_or(ManifestError::InvalidOrdersFormat)
+}
+
+// どのパッケージの注文かを保持する
+#[derive(Debug)]
+struct PackageOrders {
+    name: String,
+    version: Option<String>,
+    orders: Vec<Order>,
+}
+
+fn package_orders(manifest: &Manifest, rules: &CampaignRules) -> Result<PackageOrders, ManifestError> {
+    let orders = extract_orders(manifest, rules)?;
+    let package = manifest.package.as_ref().ok_or(ManifestError::InvalidManifest)?;
+    let version = match &package.version {
+        Some(cargo_manifest::MaybeInherited::Local(version)) => Some(version.clone()),
+        _ => None,
+    };
+    Ok(PackageOrders { name: package.name.clone(), version, orders })
+}
+
+fn extract_orders(manifest: &Manifest, rules: &CampaignRules) -> Result<Vec<Order>, ManifestError> {
+    // 不正な注文は読み飛ばす
+    let orders: Vec<Order> = order_values(manifest, rules)?
+        .iter()
+        .filter_map(|order| rules.parse_order(order).ok())
+        .collect();
+    if orders.is_empty() {
+        return Err(ManifestError::NoOrders);
+    }
+    Ok(orders)
+}
+
+// `field.workspace = true` をworkspace.packageの値で置き換える
+// 解決できなかった場合はそのフィールド名を返す
+fn resolve_inherited(value: &mut toml::Value, root: &toml::Value) -> Result<(), String> {
+    let inherited = root.get("workspace").and_then(|w| w.get("package"));
+    let package = match value.get_mut("package").and_then(|p| p.as_table_mut()) {
+        Some(package) => package,
+        None => return Ok(()),
+    };
+
+    for (field, field_value) in package.iter_mut() {
+        let is_inherited = field_value
+            .get("workspace")
+            .and_then(|w| w.as_bool())
+            .unwrap_or(false);
+        if !is_inherited {
+            continue;
+        }
+        *field_value = inherited
+            .and_then(|p| p.get(field))
+            .cloned()
+            .ok_or_else(|| field.to_string())?;
+    }
+    Ok(())
+}
+
+fn manifest_from_value(mut value: toml::Value, root: Option<&toml::Value>) -> Result<Manifest, ManifestError> {
+    // 単一ドキュメントにworkspaceが含まれていればそれをルートとして扱う
+    let resolved = match root {
+        Some(root) => resolve_inherited(&mut value, root),
+        None if value.get("workspace").is_some() => {
+            let root = value.clone();
+            resolve_inherited(&mut value, &root)
+        },
+        None => Ok(()),
+    };
+    resolved.map_err(|_| ManifestError::InvalidManifest)?;
+    to_manifest(value).map_err(|_| ManifestError::InvalidManifest)
+}
+
+fn process_manifest(format: ManifestFormat, text: &str, rules: &CampaignRules) -> Result<Vec<PackageOrders>, ManifestError> {
+    let value = format.parse(text).map_err(|_| ManifestError::InvalidManifest)?;
+    let manifest = manifest_from_value(value, None)?;
+    validate_keywords(&manifest, rules)?;
+    Ok(vec![package_orders(&manifest, rules)?])
+}
+
+#[derive(Debug)]
+struct ManifestDocument {
+    path: String,
+    format: ManifestFormat,
+    text: String,
+}
+
+impl ManifestDocument {
+    fn dir(&self) -> &str {
+        let path = self.path.trim_start_matches("./");
+        match path.rsplit_once('/') {
+            Some((dir, _)) => dir,
+            None => "",
+        }
+    }
+}
+
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.trim_start_matches("./").trim_end_matches('/').split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    pattern.len() == path.len()
+        && pattern.iter().zip(&path).all(|(p, s)| match p.strip_suffix('*') {
+            Some(prefix) => s.starts_with(prefix),
+            None => p == s,
+        })
+}
+
+// ルートのディレクトリからの相対パス。ルートの外なら None
+fn relative_dir<'a>(root_dir: &str, dir: &'a str) -> Option<&'a str> {
+    if root_dir.is_empty() {
+        return Some(dir);
+    }
+    match dir.strip_prefix(root_dir)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+fn is_member(root: &toml::Value, dir: &str) -> bool {
+    let matches = |key: &str| {
+        root.get("workspace")
+            .and_then(|w| w.get(key))
+            .and_then(|patterns| patterns.as_array())
+            .is_some_and(|patterns| patterns.iter().filter_map(|p| p.as_str()).any(|p| glob_match(p, dir)))
+    };
+    matches("members") && !matches("exclude")
+}
+
+// ルートのworkspaceを解決し、全メンバーの注文をまとめる
+fn process_workspace(documents: Vec<ManifestDocument>, rules: &CampaignRules) -> Result<Vec<PackageOrders>, ManifestError> {
+    if documents.len() == 1 {
+        let document = &documents[0];
+        return process_manifest(document.format, &document.text, rules);
+    }
+
+    let mut values = Vec::new();
+    for document in &documents {
+        let value = document.format.parse(&document.text).map_err(|_| ManifestError::InvalidManifest)?;
+        values.push((document.dir(), value));
+    }
+
+    let mut roots = values.iter().filter(|(_, value)| value.get("workspace").is_some());
+    let (root_dir, root) = match (roots.next(), roots.next()) {
+        (Some((root_dir, root)), None) => (*root_dir, root.clone()),
+        _ => return Err(ManifestError::InvalidManifest),
+    };
+
+    let mut packages = Vec::new();
+    for (dir, value) in values {
+        let is_root = value.get("workspace").is_some();
+        if value.get("package").is_none() {
+            continue;
+        }
+        // members はルートのディレクトリからの相対パスで書かれている
+        let Some(dir) = relative_dir(root_dir, dir) else {
+            return Err(ManifestError::InvalidManifest);
+        };
+        if !(is_root || is_member(&root, dir)) {
+            continue;
+        }
+        let manifest = manifest_from_value(value, Some(&root))?;
+        validate_keywords(&manifest, rules)?;
+        match package_orders(&manifest, rules) {
+            Ok(member) => packages.push(member),
+            Err(ManifestError::NoMetadata) | Err(ManifestError::NoOrders) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if packages.is_empty() {
+        return Err(ManifestError::NoOrders);
+    }
+    Ok(packages)
+}
+
+fn multipart_error(e: MultipartError) -> BodyError {
+    match e.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => BodyError::TooLarge,
+        _ => BodyError::Unreadable,
+    }
+}
+
+// 全ファイルの合計サイズがMANIFEST_MAX_SIZEを超えないか読みながら確認する
+async fn read_documents(mut multipart: Multipart) -> Result<Vec<ManifestDocument>, BodyError> {
+    let limit = max_manifest_size();
+    let mut total = 0;
+    let mut documents = Vec::new();
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        let path = field.file_name().or(field.name()).unwrap_or("Cargo.toml").to_string();
+        let format = ManifestFormat::from_file(field.content_type(), &path);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+            total += chunk.len();
+            if total > limit {
+                return Err(BodyError::TooLarge);
             }
+            bytes.extend_from_slice(&chunk);
+        }
+        let text = String::from_utf8(bytes).map_err(|_| BodyError::InvalidUtf8)?;
+        documents.push(ManifestDocument { path, format, text });
+    }
+    Ok(documents)
+}
 
-            let metadata: serde_yml::Value = if let Some(metadata) = package.metadata {
-                serde_yml::from_value(serde_yml::to_value(metadata).unwrap()).unwrap()
-            } else {
-                return (StatusCode::NO_CONTENT, "No metadata found".to_string());
-            };
-
-            let orders = if let Some(orders) = metadata.get("orders") {
-                if let Some(orders) = orders.as_sequence() {
-                    orders
-                } else {
-                    return (StatusCode::NO_CONTENT, "Invalid orders format".to_string());
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Problem {
+    ParseError {
+        format: &'static str,
+        #[serde(flatten)]
+        error: ParseError,
+    },
+    MissingField {
+        field: String,
+    },
+    InvalidField {
+        field: String,
+        value: String,
+        reason: String,
+    },
+    MagicKeywordMissing {
+        keywords: Vec<String>,
+        keyword_match: KeywordMatch,
+    },
+    InvalidOrders {
+        reason: String,
+    },
+    SkippedOrder {
+        index: usize,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestReport {
+    valid: bool,
+    problems: Vec<Problem>,
+    orders: Vec<Order>,
+}
+
+// package直下の項目を検査する(未解決の継承値は検査しない)
+// Cargoと同じくnameのみ必須で、versionとeditionは指定されている場合に形式を確認する
+fn check_package_fields(package: &toml::Value, problems: &mut Vec<Problem>) {
+    for field in ["name", "version", "edition"] {
+        let value = match package.get(field) {
+            Some(value) => value,
+            None if field == "name" => {
+                problems.push(Problem::MissingField { field: format!("package.{}", field) });
+                continue;
+            },
+            None => continue,
+        };
+        if value.is_table() {
+            continue;
+        }
+        let reason = match (field, value.as_str()) {
+            (_, None) => Some("must be a string".to_string()),
+            ("version", Some(version)) => semver::Version::parse(version).err().map(|e| e.to_string()),
+            ("edition", Some(edition)) if !EDITIONS.contains(&edition) => Some(format!("must be one of {}", EDITIONS.join(", "))),
+            _ => None,
+        };
+        if let Some(reason) = reason {
+            problems.push(Problem::InvalidField {
+                field: format!("package.{}", field),
+                value: value.to_string(),
+                reason,
+            });
+        }
+    }
+}
+
+fn report_manifest(format: ManifestFormat, text: &str, rules: &CampaignRules) -> ManifestReport {
+    let mut problems = Vec::new();
+    let mut orders = Vec::new();
+
+    let mut value = match format.parse(text) {
+        Ok(value) => value,
+        Err(error) => {
+            problems.push(Problem::ParseError { format: format.name(), error });
+            return ManifestReport { valid: false, problems, orders };
+        }
+    };
+
+    if value.get("workspace").is_some() {
+        let root = value.clone();
+        if let Err(field) = resolve_inherited(&mut value, &root) {
+            problems.push(Problem::MissingField { field: format!("workspace.package.{}", field) });
+        }
+    }
+
+    match value.get("package") {
+        Some(package) => check_package_fields(package, &mut problems),
+        None => problems.push(Problem::MissingField { field: "package".to_string() }),
+    }
+
+    let manifest = match to_manifest(value) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            problems.push(Problem::ParseError { format: format.name(), error });
+            return ManifestReport { valid: false, problems, orders };
+        }
+    };
+
+    if manifest.package.is_some() {
+        if validate_keywords(&manifest, rules).is_err() {
+            problems.push(Problem::MagicKeywordMissing {
+                keywords: rules.keywords.clone(),
+                keyword_match: rules.keyword_match,
+            });
+        }
+
+        match order_values(&manifest, rules) {
+            Ok(values) => {
+                for (index, order) in values.iter().enumerate() {
+                    match rules.parse_order(order) {
+                        Ok(order) => orders.push(order),
+                        Err(reason) => problems.push(Problem::SkippedOrder { index, reason }),
+                    }
                 }
-            } else {
-                return (StatusCode::NO_CONTENT, "No order found".to_string());
-            };
+            },
+            Err(e) => problems.push(Problem::InvalidOrders {
+                reason: match e {
+                    ManifestError::NoMetadata => "no metadata found".to_string(),
+                    ManifestError::InvalidOrdersFormat => format!("{} is not an array", rules.orders_key),
+                    _ => format!("no {} found", rules.orders_key),
+                },
+            }),
+        }
+    }
+
+    ManifestReport { valid: problems.is_empty(), problems, orders }
+}
+
+#[derive(Debug, Serialize)]
+struct OrderLine {
+    item: String,
+    quantity: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct OrderSummary {
+    orders: Vec<OrderLine>,
+    distinct_items: usize,
+    total_quantity: u64,
+}
 
-            let mut parsed_orders: Vec<Order> = Vec::new();
+impl OrderSummary {
+    // aggregateの場合は同じitemをまとめて名前順に並べる
+    fn new(orders: Vec<Order>, aggregate: bool) -> Self {
+        let orders: Vec<OrderLine> = if aggregate {
+            let mut totals: BTreeMap<String, u64> = BTreeMap::new();
             for order in orders {
-                let item = if let Some(item) = order.get("item").and_then(|i| i.as_str()) {
-                    item.to_string()
-                } else {
-                    continue;
-                };
-                
-                let quantity = if let Some(quantity) = order.get("quantity").and_then(|q| q.as_u64()) {
-                    quantity as u32
-                } else {
-                    continue;
-                };
-
-                parsed_orders.push(Order { item, quantity });
+                *totals.entry(order.item).or_insert(0) += order.quantity as u64;
             }
+            totals.into_iter().map(|(item, quantity)| OrderLine { item, quantity }).collect()
+        } else {
+            orders.into_iter().map(|order| OrderLine { item: order.item, quantity: order.quantity as u64 }).collect()
+        };
+
+        let mut items: Vec<&str> = orders.iter().map(|order| order.item.as_str()).collect();
+        items.sort();
+        items.dedup();
+
+        Self {
+            distinct_items: items.len(),
+            total_quantity: orders.iter().map(|order| order.quantity).sum(),
+            orders,
+        }
+    }
+
+    fn to_text(&self, with_total: bool) -> String {
+        let mut lines: Vec<String> = self.orders.iter().map(|order| format!("{}: {}", order.item, order.quantity)).collect();
+        if with_total {
+            lines.push(format!("Total: {}", self.total_quantity));
+        }
+        lines.join("\n")
+    }
+}
 
-            if parsed_orders.is_empty() {
-                return (StatusCode::NO_CONTENT, "No order found".to_string());
+#[derive(Deserialize)]
+pub struct ManifestQuery {
+    #[serde(default)]
+    report: bool,
+    #[serde(default)]
+    aggregate: bool,
+    #[serde(default)]
+    store: bool,
+    campaign: Option<String>,
+}
+
+#[derive(Debug)]
+enum BodyError {
+    InvalidContentType,
+    UnsupportedMediaType,
+    UnsupportedCharset,
+    InvalidContentLength,
+    TooLarge,
+    Unreadable,
+    InvalidUtf8,
+}
+
+impl IntoResponse for BodyError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::InvalidContentType => (StatusCode::BAD_REQUEST, "Invalid Content-Type header"),
+            Self::UnsupportedMediaType => (StatusCode::UNSUPPORTED_MEDIA_TYPE, ""),
+            Self::UnsupportedCharset => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported charset"),
+            Self::InvalidContentLength => (StatusCode::BAD_REQUEST, "Invalid Content-Length header"),
+            Self::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "Manifest too large"),
+            Self::Unreadable => (StatusCode::BAD_REQUEST, "Failed to read body"),
+            Self::InvalidUtf8 => (StatusCode::BAD_REQUEST, "Body is not valid UTF-8"),
+        }.into_response()
+    }
+}
+
+// `application/toml; charset=utf-8` のようなパラメータ付きの値からメディアタイプを取り出す
+fn media_type(headers: &HeaderMap) -> Result<Option<String>, BodyError> {
+    let content_type = match headers.get("Content-Type") {
+        Some(content_type) => content_type.to_str().map_err(|_| BodyError::InvalidContentType)?,
+        None => return Ok(None),
+    };
+
+    let mut parts = content_type.split(';');
+    let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    for param in parts {
+        if let Some((name, value)) = param.split_once('=') {
+            let value = value.trim().trim_matches('"');
+            if name.trim().eq_ignore_ascii_case("charset") && !value.eq_ignore_ascii_case("utf-8") {
+                return Err(BodyError::UnsupportedCharset);
             }
+        }
+    }
+    Ok(Some(media_type))
+}
 
-            let result: Vec<String> = parsed_orders.iter().map(|order| order.to_string()).collect();
-            (StatusCode::OK, result.join("\n"))
+// Content-Typeが無い場合の扱いはエンドポイントごとに異なるのでNoneを返す
+fn request_format(headers: &HeaderMap) -> Result<Option<ManifestFormat>, BodyError> {
+    match media_type(headers)? {
+        Some(content_type) => match ManifestFormat::from_content_type(&content_type) {
+            Some(format) => Ok(Some(format)),
+            None => Err(BodyError::UnsupportedMediaType),
         },
-        Some("application/json") => {
-            let content_size:usize = headers.get("Content-Length").expect("No Content-Length header").to_str().unwrap().parse().expect("Invalid Content-Length header");
-            let bytes = to_bytes(body, content_size).await.expect("Invalid body");
-            let json_str = String::from_utf8(bytes.to_vec()).expect("Invalid body");
-
-            // JSONを直接Manifestに変換
-            let manifest: Manifest = if let Ok(manifest) = serde_json::from_str(&json_str) {
-                manifest
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-            };
+        None => Ok(None),
+    }
+}
 
-            // Cargoマニフェストの検証
-            let package = if let Some(package) = manifest.package {
-                package
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-            };
-
-            if let Some(keywords) = package.keywords {
-                match keywords {
-                    cargo_manifest::MaybeInherited::Local(keywords) => {
-                        if !keywords.contains(&"Christmas 2024".to_string()) {
-                            return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                        }
-                    },
-                    _ => return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string())
-                }
-            } else {
-                return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string());
-            }
+// multipartの抽出にもaxumの既定の上限(2MB)ではなくMANIFEST_MAX_SIZEを適用する
+pub fn body_limit() -> DefaultBodyLimit {
+    DefaultBodyLimit::max(max_manifest_size())
+}
 
-            // 以降は既存のJSONパース処理を継続
-            let json: serde_json::Value = serde_json::from_str(&json_str).expect("Invalid JSON");
-            let package = if let Some(package) = json.get("package") {
-                package
-            } else {
-                return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-            };
-
-            // Check keywords
-            let keywords = if let Some(keywords) = package.get("keywords") {
-                if let Some(keywords) = keywords.as_array() {
-                    keywords
-                } else {
-                    return (StatusCode::BAD_REQUEST, "Invalid keywords format".to_string());
-                }
-            } else {
-                return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string());
-            };
+fn check_content_length(headers: &HeaderMap) -> Result<(), BodyError> {
+    if let Some(length) = headers.get("Content-Length") {
+        let length: usize = length
+            .to_str()
+            .ok()
+            .and_then(|length| length.trim().parse().ok())
+            .ok_or(BodyError::InvalidContentLength)?;
+        if length > max_manifest_size() {
+            return Err(BodyError::TooLarge);
+        }
+    }
+    Ok(())
+}
 
-            if !keywords.iter().any(|k| k.as_str() == Some("Christmas 2024")) {
-                return (StatusCode::BAD_REQUEST, "Magic keyword not provided".to_string());
+// Content-Lengthが無い(chunked)場合もフレームごとに読みながら上限を確認する
+async fn read_text(headers: &HeaderMap, mut body: Body) -> Result<String, BodyError> {
+    let limit = max_manifest_size();
+    check_content_length(headers)?;
+
+    let mut bytes = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| BodyError::Unreadable)?;
+        if let Some(data) = frame.data_ref() {
+            if bytes.len() + data.len() > limit {
+                return Err(BodyError::TooLarge);
             }
+            bytes.extend_from_slice(data);
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| BodyError::InvalidUtf8)
+}
+
+#[axum::debug_handler]
+pub async fn return_manifest(
+    State(state): State<StateOrders>,
+    Query(query): Query<ManifestQuery>,
+    request: Request,
+) -> Response {
+    let rules = match manifest_rules().campaign(query.campaign.as_deref()) {
+        Some(rules) => rules,
+        None => return (StatusCode::BAD_REQUEST, "Unknown campaign".to_string()).into_response(),
+    };
+
+    let headers = request.headers().clone();
+    let wants_json = headers
+        .get("Accept")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+
+    let content_type = match media_type(&headers) {
+        Ok(content_type) => content_type,
+        Err(e) => return e.into_response(),
+    };
+    if content_type.as_deref() == Some("multipart/form-data") {
+        if let Err(e) = check_content_length(&headers) {
+            return e.into_response();
+        }
+        let multipart = match Multipart::from_request(request, &()).await {
+            Ok(multipart) => multipart,
+            Err(_) => return BodyError::InvalidContentType.into_response(),
+        };
+        let result = match read_documents(multipart).await {
+            Ok(documents) if documents.is_empty() => Err(ManifestError::InvalidManifest),
+            Ok(documents) => process_workspace(documents, rules),
+            Err(e) => return e.into_response(),
+        };
+        return finish_orders(&state, &query, result, wants_json).await;
+    }
+
+    let format = match request_format(&headers) {
+        Ok(Some(format)) => format,
+        Ok(None) => {
+            println!("No Content-Type header");
+            return (StatusCode::OK, "No Content-Type header".to_string()).into_response();
+        },
+        Err(e) => {
+            println!("Unsupported Content-Type");
+            return e.into_response();
+        },
+    };
+    let text = match read_text(&headers, request.into_body()).await {
+        Ok(text) => text,
+        Err(e) => return e.into_response(),
+    };
+
+    if query.report {
+        let report = report_manifest(format, &text, rules);
+        return (
+            StatusCode::OK,
+            [("Content-Type", "application/json")],
+            serde_json::to_string(&report).unwrap(),
+        ).into_response();
+    }
+
+    finish_orders(&state, &query, process_manifest(format, &text, rules), wants_json).await
+}
 
-            // Get metadata and orders
-            let metadata = if let Some(metadata) = package.get("metadata") {
-                metadata
+async fn store_orders(pool: &sqlx::PgPool, packages: &[PackageOrders]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for package in packages {
+        for order in &package.orders {
+            sqlx::query(INSERT_ORDER_SQL)
+                .bind(Uuid::new_v4())
+                .bind(&package.name)
+                .bind(&package.version)
+                .bind(&order.item)
+                .bind(order.quantity as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await
+}
+
+async fn finish_orders(
+    state: &StateOrders,
+    query: &ManifestQuery,
+    result: Result<Vec<PackageOrders>, ManifestError>,
+    wants_json: bool,
+) -> Response {
+    if let (true, Ok(packages)) = (query.store, &result) {
+        if let Err(e) = store_orders(&state.pool, packages).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store orders: {}", e)).into_response();
+        }
+    }
+    respond_orders(result, query.aggregate, wants_json)
+}
+
+fn respond_orders(result: Result<Vec<PackageOrders>, ManifestError>, aggregate: bool, wants_json: bool) -> Response {
+    match result {
+        Ok(packages) => {
+            let orders = packages.into_iter().flat_map(|package| package.orders).collect();
+            let summary = OrderSummary::new(orders, aggregate);
+            if wants_json {
+                (
+                    StatusCode::OK,
+                    [("Content-Type", "application/json")],
+                    serde_json::to_string(&summary).unwrap(),
+                ).into_response()
             } else {
-                return (StatusCode::NO_CONTENT, "No metadata found".to_string());
-            };
-
-            let orders = if let Some(orders) = metadata.get("orders") {
-                if let Some(orders) = orders.as_array() {
-                    orders
-                } else {
-                    return (StatusCode::NO_CONTENT, "Invalid orders format".to_string());
+                (StatusCode::OK, summary.to_text(aggregate)).into_response()
+            }
+        },
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StoredOrder {
+    id: Uuid,
+    package: String,
+    version: Option<String>,
+    item: String,
+    quantity: i64,
+    received_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ItemTotal {
+    item: String,
+    quantity: i64,
+}
+
+#[derive(Deserialize)]
+pub struct OrderFilter {
+    package: Option<String>,
+    item: Option<String>,
+}
+
+pub async fn list_orders(
+    State(state): State<StateOrders>,
+    Query(filter): Query<OrderFilter>,
+) -> impl IntoResponse {
+    let pool = &*state.pool;
+
+    match sqlx::query_as::<_, StoredOrder>(SELECT_ORDERS_SQL)
+        .bind(&filter.package)
+        .bind(&filter.item)
+        .fetch_all(pool)
+        .await {
+            Ok(orders) => Json(orders).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch orders: {}", e)).into_response(),
+        }
+}
+
+pub async fn order_totals(
+    State(state): State<StateOrders>,
+    Query(filter): Query<OrderFilter>,
+) -> impl IntoResponse {
+    let pool = &*state.pool;
+
+    match sqlx::query_as::<_, ItemTotal>(SELECT_TOTALS_SQL)
+        .bind(&filter.package)
+        .bind(&filter.item)
+        .fetch_all(pool)
+        .await {
+            Ok(totals) => Json(totals).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to fetch totals: {}", e)).into_response(),
+        }
+}
+
+#[derive(Debug, Serialize)]
+struct DependencyInfo {
+    name: String,
+    source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    features: Vec<String>,
+    optional: bool,
+}
+
+impl DependencyInfo {
+    fn new(name: &str, dependency: &cargo_manifest::Dependency) -> Self {
+        let mut info = Self {
+            name: name.to_string(),
+            source: "registry",
+            version: None,
+            git: None,
+            path: None,
+            features: Vec::new(),
+            optional: false,
+        };
+        match dependency {
+            cargo_manifest::Dependency::Simple(version) => {
+                info.version = Some(version.clone());
+            },
+            cargo_manifest::Dependency::Inherited(_) => {
+                info.source = "workspace";
+            },
+            cargo_manifest::Dependency::Detailed(detail) => {
+                info.version = detail.version.clone();
+                info.git = detail.git.clone();
+                info.path = detail.path.clone();
+                info.features = detail.features.clone().unwrap_or_default();
+                info.optional = detail.optional.unwrap_or(false);
+                if info.git.is_some() {
+                    info.source = "git";
+                } else if info.path.is_some() {
+                    info.source = "path";
                 }
-            } else {
-                return (StatusCode::NO_CONTENT, "No order found".to_string());
-            };
+            },
+        }
+        info
+    }
+}
 
-            let mut parsed_orders: Vec<Order> = Vec::new();
-            for order in orders {
-                let item = if let Some(item) = order.get("item").and_then(|i| i.as_str()) {
-                    item.to_string()
-                } else {
-                    continue;
-                };
-                
-                let quantity = if let Some(quantity) = order.get("quantity").and_then(|q| q.as_u64()) {
-                    quantity as u32
-                } else {
-                    continue;
-                };
-
-                parsed_orders.push(Order { item, quantity });
+#[derive(Debug, Default, Serialize)]
+struct DependencyGroups {
+    dependencies: Vec<DependencyInfo>,
+    dev_dependencies: Vec<DependencyInfo>,
+    build_dependencies: Vec<DependencyInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct DependencyAnalysis {
+    package: Option<String>,
+    #[serde(flatten)]
+    groups: DependencyGroups,
+    targets: BTreeMap<String, DependencyGroups>,
+    features: BTreeMap<String, Vec<String>>,
+    default_features: Vec<String>,
+    git_dependencies: Vec<String>,
+    path_dependencies: Vec<String>,
+    warnings: Vec<String>,
+}
+
+fn dependency_list(deps: Option<&cargo_manifest::DepsSet>) -> Vec<DependencyInfo> {
+    deps.map(|deps| deps.iter().map(|(name, dep)| DependencyInfo::new(name, dep)).collect())
+        .unwrap_or_default()
+}
+
+// defaultから辿れるfeatureを全て集める
+fn default_features(features: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    let mut enabled = BTreeSet::new();
+    let mut stack = vec!["default".to_string()];
+    while let Some(feature) = stack.pop() {
+        if let Some(children) = features.get(&feature) {
+            for child in children {
+                if features.contains_key(child) && enabled.insert(child.clone()) {
+                    stack.push(child.clone());
+                }
             }
+        }
+    }
+    enabled.into_iter().collect()
+}
+
+fn analyze(manifest: &Manifest) -> DependencyAnalysis {
+    let groups = DependencyGroups {
+        dependencies: dependency_list(manifest.dependencies.as_ref()),
+        dev_dependencies: dependency_list(manifest.dev_dependencies.as_ref()),
+        build_dependencies: dependency_list(manifest.build_dependencies.as_ref()),
+    };
+
+    let targets: BTreeMap<String, DependencyGroups> = manifest.target.iter()
+        .flatten()
+        .map(|(cfg, target)| (cfg.clone(), DependencyGroups {
+            dependencies: dependency_list(Some(&target.dependencies)),
+            dev_dependencies: dependency_list(Some(&target.dev_dependencies)),
+            build_dependencies: dependency_list(Some(&target.build_dependencies)),
+        }))
+        .collect();
 
-            if parsed_orders.is_empty() {
-                return (StatusCode::NO_CONTENT, "No order found".to_string());
+    let features: BTreeMap<String, Vec<String>> = manifest.features.clone()
+        .map(|features| features.into_iter().collect())
+        .unwrap_or_default();
+
+    let mut git_dependencies = BTreeSet::new();
+    let mut path_dependencies = BTreeSet::new();
+    let mut warnings = Vec::new();
+
+    let all_groups = std::iter::once(("".to_string(), &groups))
+        .chain(targets.iter().map(|(cfg, groups)| (format!(" ({})", cfg), groups)));
+    for (scope, groups) in all_groups {
+        for (kind, deps) in [
+            ("dependencies", &groups.dependencies),
+            ("dev-dependencies", &groups.dev_dependencies),
+            ("build-dependencies", &groups.build_dependencies),
+        ] {
+            for dep in deps {
+                match dep.source {
+                    "git" => { git_dependencies.insert(dep.name.clone()); },
+                    "path" => { path_dependencies.insert(dep.name.clone()); },
+                    _ => {}
+                }
+                if dep.version.as_deref().is_some_and(|version| version.contains('*')) {
+                    warnings.push(format!(
+                        "{}{}: {} uses a wildcard version requirement \"{}\"",
+                        kind, scope, dep.name, dep.version.as_deref().unwrap_or_default()
+                    ));
+                }
             }
+        }
+    }
 
-            let result: Vec<String> = parsed_orders.iter().map(|order| order.to_string()).collect();
-            (StatusCode::OK, result.join("\n"))
-        },
-        Some(_) => {
-            println!("Unsupported Content-Type");
-            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "".to_string())
-        },
-        None => {
-            println!("No Content-Type header");
-            (StatusCode::OK, "No Content-Type header".to_string())
+    DependencyAnalysis {
+        package: manifest.package.as_ref().map(|package| package.name.clone()),
+        default_features: default_features(&features),
+        groups,
+        targets,
+        features,
+        git_dependencies: git_dependencies.into_iter().collect(),
+        path_dependencies: path_dependencies.into_iter().collect(),
+        warnings,
+    }
+}
+
+pub async fn analyze_manifest(headers: HeaderMap, body: Body) -> Response {
+    let format = match request_format(&headers) {
+        Ok(Some(format)) => format,
+        Ok(None) => return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "No Content-Type header".to_string()).into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let text = match read_text(&headers, body).await {
+        Ok(text) => text,
+        Err(e) => return e.into_response(),
+    };
+
+    let manifest = match format.parse(&text).ok().and_then(|value| manifest_from_value(value, None).ok()) {
+        Some(manifest) => manifest,
+        None => return ManifestError::InvalidManifest.into_response(),
+    };
+
+    (
+        StatusCode::OK,
+        [("Content-Type", "application/json")],
+        serde_json::to_string(&analyze(&manifest)).unwrap(),
+    ).into_response()
+}
+
+pub async fn convert_manifest(headers: HeaderMap, body: Body) -> Response {
+    let from = match request_format(&headers) {
+        Ok(Some(format)) => format,
+        Ok(None) => return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "No Content-Type header".to_string()).into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let to = match headers.get("Accept").and_then(|v| v.to_str().ok()).and_then(ManifestFormat::from_accept) {
+        Some(format) => format,
+        None => return (StatusCode::NOT_ACCEPTABLE, "".to_string()).into_response(),
+    };
+    let text = match read_text(&headers, body).await {
+        Ok(text) => text,
+        Err(e) => return e.into_response(),
+    };
+
+    let manifest = match from.parse(&text).ok().and_then(|value| to_manifest(value).ok()) {
+        Some(manifest) => manifest,
+        None => return ManifestError::InvalidManifest.into_response(),
+    };
+
+    match convert(&manifest, to) {
+        Ok(converted) => (StatusCode::OK, [("Content-Type", to.content_type())], converted).into_response(),
+        Err(reason) => (StatusCode::UNPROCESSABLE_ENTITY, reason.to_string()).into_response(),
+    }
+}
+
+// 変換結果を読み戻して同じManifestになることを保証する
+fn convert(manifest: &Manifest, to: ManifestFormat) -> Result<String, &'static str> {
+    let converted = to.serialize(manifest).ok_or("Failed to convert manifest")?;
+    if to.deserialize(&converted).as_ref() != Some(manifest) {
+        return Err("Manifest does not round-trip");
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[package]
+name = "north-pole"
+version = "0.1.0"
+edition = "2021"
+keywords = ["Christmas 2024"]
+
+[package.metadata]
+orders = [{ item = "Toy car", quantity = 2 }]
+
+[dependencies]
+serde = { version = "1.0", features = ["derive"] }
+"#;
+
+    #[test]
+    fn converts_to_every_format() {
+        let manifest = to_manifest(ManifestFormat::Toml.parse(MANIFEST).unwrap()).unwrap();
+        for to in [ManifestFormat::Toml, ManifestFormat::Yaml, ManifestFormat::Json] {
+            let converted = convert(&manifest, to).unwrap();
+            // 変換結果はそのまま入力としても受け付けられる
+            let parsed = to.parse(&converted).and_then(to_manifest).unwrap();
+            assert!(parsed == manifest, "{} does not parse back", to.name());
+        }
+    }
+
+    #[test]
+    fn accepts_null_fields() {
+        let text = r#"{"package": {"name": "north-pole", "version": "0.1.0", "description": null}}"#;
+        let manifest = to_manifest(ManifestFormat::Json.parse(text).unwrap()).unwrap();
+        assert_eq!(manifest.package.map(|package| package.name), Some("north-pole".to_string()));
+    }
+
+    #[test]
+    fn rejects_redefined_order_fields() {
+        let mut campaign: CampaignRules = toml::from_str(r#"
+keywords = ["Easter 2025"]
+order.quantity = { type = "number" }
+"#).unwrap();
+        assert!(campaign.build_schema().is_err());
+
+        let mut campaign: CampaignRules = toml::from_str(r#"
+keywords = ["Easter 2025"]
+order.price = { type = "number", minimum = 0 }
+"#).unwrap();
+        campaign.build_schema().unwrap();
+        assert!(campaign.schema.contains_key("quantity") && campaign.schema.contains_key("price"));
+    }
+
+    fn document(path: &str, text: &str) -> ManifestDocument {
+        ManifestDocument { path: path.to_string(), format: ManifestFormat::Toml, text: text.to_string() }
+    }
+
+    const WORKSPACE: &str = r#"
+[workspace]
+members = ["crates/*"]
+"#;
+
+    #[test]
+    fn resolves_members_under_subdirectory() {
+        let rules = ManifestRules::default();
+        let rules = rules.campaign(None).unwrap();
+        for prefix in ["", "ws/"] {
+            let documents = vec![
+                document(&format!("{}Cargo.toml", prefix), WORKSPACE),
+                document(&format!("{}crates/a/Cargo.toml", prefix), MANIFEST),
+            ];
+            let packages = process_workspace(documents, rules).unwrap();
+            assert_eq!(packages.len(), 1, "members under {:?} are not resolved", prefix);
         }
     }
 
+    #[test]
+    fn rejects_members_outside_root() {
+        let rules = ManifestRules::default();
+        let documents = vec![
+            document("ws/Cargo.toml", WORKSPACE),
+            document("other/crates/a/Cargo.toml", MANIFEST),
+        ];
+        assert!(matches!(process_workspace(documents, rules.campaign(None).unwrap()), Err(ManifestError::InvalidManifest)));
+    }
 }
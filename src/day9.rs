@@ -1,17 +1,119 @@
 use axum::{
-    body::{Body, to_bytes}, extract::State, http::{HeaderMap, StatusCode}, response::IntoResponse  
+    body::{Body, to_bytes}, extract::{Request, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}
 };
 use leaky_bucket::RateLimiter;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::{Mutex, Arc};
+use std::task::{Context, Poll};
 use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
 
 
 pub const MAX_MILK: usize = 5;
 
+// バケツの容量と補充間隔
+#[derive(Debug, Clone, Copy)]
+pub struct MilkPolicy {
+    pub max: usize,
+    pub initial: usize,
+    pub interval: Duration,
+}
+
+impl MilkPolicy {
+    pub const fn new(max: usize, interval: Duration) -> Self {
+        Self { max, initial: max, interval }
+    }
+
+    pub fn build_limiter(&self) -> RateLimiter {
+        RateLimiter::builder()
+            .max(self.max)
+            .initial(self.initial)
+            .interval(self.interval)
+            .build()
+    }
+}
+
+impl Default for MilkPolicy {
+    fn default() -> Self {
+        Self::new(MAX_MILK, Duration::from_secs(1))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MilkState {
     pub limiter: Arc<Mutex<RateLimiter>>,
+    pub policy: MilkPolicy,
+}
+
+impl MilkState {
+    pub fn new(policy: MilkPolicy) -> Self {
+        Self {
+            limiter: Arc::new(Mutex::new(policy.build_limiter())),
+            policy,
+        }
+    }
+
+    pub fn try_withdraw(&self) -> bool {
+        self.limiter.lock().unwrap().try_acquire(1)
+    }
+
+    pub fn refill(&self) {
+        *self.limiter.lock().unwrap() = self.policy.build_limiter();
+    }
+}
+
+// 任意のルートに同じバケツを適用するためのLayer
+#[derive(Debug, Clone)]
+pub struct MilkLayer {
+    state: MilkState,
+}
+
+impl MilkLayer {
+    pub fn new(policy: MilkPolicy) -> Self {
+        Self { state: MilkState::new(policy) }
+    }
+}
+
+impl<S> Layer<S> for MilkLayer {
+    type Service = MilkService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MilkService {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MilkService<S> {
+    inner: S,
+    state: MilkState,
+}
+
+impl<S> Service<Request> for MilkService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if !self.state.try_withdraw() {
+            return Box::pin(async {
+                Ok((StatusCode::TOO_MANY_REQUESTS, "No milk available\n").into_response())
+            });
+        }
+        Box::pin(self.inner.call(req))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -41,7 +143,7 @@ pub async fn milk_and_cookies(
 ) -> impl IntoResponse {
     match headers.get("Content-Type").map(|v| v.to_str().unwrap()) {
         Some("application/json") => {
-            if !milk_state.limiter.lock().unwrap().try_acquire(1) {
+            if !milk_state.try_withdraw() {
                 return (StatusCode::TOO_MANY_REQUESTS, "No milk available\n".to_string());
             }
 
@@ -81,7 +183,7 @@ pub async fn milk_and_cookies(
             }
         },
         Some(_) | None => {
-            if milk_state.limiter.lock().unwrap().try_acquire(1) {
+            if milk_state.try_withdraw() {
                 (StatusCode::OK, "Milk withdrawn\n".to_string())
             } else {
                 (StatusCode::TOO_MANY_REQUESTS, "No milk available\n".to_string())
@@ -94,10 +196,6 @@ pub async fn milk_and_cookies(
 pub async fn refill_milk(
     State(milk_state): State<MilkState>,
 ) -> impl IntoResponse {
-    *milk_state.limiter.lock().unwrap() = RateLimiter::builder()
-        .max(MAX_MILK)
-        .initial(MAX_MILK)
-        .interval(Duration::from_secs(1))
-        .build();
+    milk_state.refill();
     StatusCode::OK
 }
\ No newline at end of file
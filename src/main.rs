@@ -1,10 +1,9 @@
 use axum::{
-    routing::get,
+    routing::{get, post},
     Router,    
 };
 use tower_http::services::ServeDir;
 use std::sync::{Mutex, Arc};
-use leaky_bucket::RateLimiter;
 use std::time::Duration;
 use rand::SeedableRng;
 use shuttle_shared_db;
@@ -23,19 +22,33 @@ mod day23;
 async fn main(
     #[shuttle_shared_db::Postgres] pool: sqlx::PgPool
 ) -> shuttle_axum::ShuttleAxum {
-    let milk_state = day9::MilkState {
-        limiter: Arc::new(Mutex::new(RateLimiter::builder()
-            .max(day9::MAX_MILK)
-            .initial(day9::MAX_MILK)
-            .interval(Duration::from_secs(1))
-            .build())),
-    };
+    let milk_state = day9::MilkState::new(day9::MilkPolicy::default());
+
+    // 重いエンドポイント用のルートごとのバケツ
+    let wrap_limit = day9::MilkLayer::new(day9::MilkPolicy::new(20, Duration::from_millis(100)));
+    let draft_limit = day9::MilkLayer::new(day9::MilkPolicy::new(10, Duration::from_millis(200)));
 
     let board_state = day12::StateBoard {
         board: Arc::new(Mutex::new(day12::Board::new())),
         seed: Arc::new(Mutex::new(rand::rngs::StdRng::seed_from_u64(2024))),
     };
 
+    day5::init().map_err(shuttle_runtime::CustomError::msg)?;
+    sqlx::query(day5::MAKE_DB_SQL)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let order_state = day5::StateOrders {
+        pool: Arc::new(pool.clone()),
+    };
+
+    day16::init().map_err(shuttle_runtime::CustomError::msg)?;
+    sqlx::query(day16::MAKE_DB_SQL)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let gift_state = day16::StateGifts::new(pool.clone());
+
     sqlx::query(day19::MAKE_DB_SQL)
         .execute(&pool)
         .await
@@ -50,9 +63,22 @@ async fn main(
         .route("/-1/seek", get(day1::seek_and_found)) // day1 task 2
         .route("/2/dest", get(day2::from_key_calc)) // day2 task 1
         .route("/2/key", get(day2::from_to_calc)) // day2 task 2
-        .route("/2/v6/dest", get(day2::from_key_calc_v6)) // day2 task 3
-        .route("/2/v6/key", get(day2::from_to_calc_v6)) // day2 task 3
-        .route("/5/manifest", get(day5::return_manifest).post(day5::return_manifest)) // day5 task 1
+        .route("/2/v6/dest", get(day2::from_key_calc)) // day2 task 3
+        .route("/2/v6/key", get(day2::from_to_calc)) // day2 task 3
+        .route("/2/block/dest", get(day2::from_key_calc_block))
+        .route("/2/block/source", get(day2::to_key_calc_block))
+        .route("/2/batch", post(day2::batch_calc))
+        .route("/2/mac/dest", get(day2::from_key_calc_mac))
+        .route("/2/mac/key", get(day2::from_to_calc_mac))
+        .route("/2/socket/dest", get(day2::from_key_calc_socket))
+        .route("/2/socket/key", get(day2::from_to_calc_socket))
+        .route("/2/solve", post(day2::solve_key))
+        .route("/5/manifest", get(day5::return_manifest).post(day5::return_manifest).layer(day5::body_limit())) // day5 task 1
+        .route("/5/analyze", post(day5::analyze_manifest))
+        .route("/5/convert", post(day5::convert_manifest))
+        .route("/5/orders", get(day5::list_orders))
+        .route("/5/orders/totals", get(day5::order_totals))
+        .with_state(order_state)
         .route("/9/milk", get(day9::milk_and_cookies).post(day9::milk_and_cookies))// day9 task 1
         .route("/9/refill", get(day9::refill_milk).post(day9::refill_milk)) // day9 task 2
         .with_state(milk_state)
@@ -61,14 +87,19 @@ async fn main(
         .route("/12/board", get(day12::get_board).post(day12::get_board)) // day12 task 3
         .route("/12/random-board", get(day12::rand_board).post(day12::rand_board)) // day12 task 4
         .with_state(board_state)
-        .route("/16/wrap", get(day16::wrap).post(day16::wrap)) // day16 task 1
+        .route("/16/wrap", get(day16::wrap).post(day16::wrap).layer(wrap_limit)) // day16 task 1
         .route("/16/unwrap", get(day16::unwrap).post(day16::unwrap)) // day16 task 2
         .route("/16/decode", get(day16::decode_santa).post(day16::decode_santa)) // day16 task 2
+        .route("/16/refresh", post(day16::refresh))
+        .route("/16/revoke", post(day16::revoke))
+        .route("/16/inspect", get(day16::inspect).post(day16::inspect))
+        .route("/.well-known/jwks.json", get(day16::jwks))
+        .with_state(gift_state)
         .route("/19/reset", get(day19::reset_db).post(day19::reset_db)) // day19 task 1
         .route("/19/cite/:id", get(day19::cite).post(day19::cite)) // day19 task 2
         .route("/19/remove/:id", get(day19::remove_db).delete(day19::remove_db)) // day19 task 3
         .route("/19/undo/:id", get(day19::undo_db).put(day19::undo_db).post(day19::undo_db)) // day19 task 3
-        .route("/19/draft", get(day19::draft_db).post(day19::draft_db)) // day19 task 4
+        .route("/19/draft", get(day19::draft_db).post(day19::draft_db).layer(draft_limit)) // day19 task 4
         .route("/19/list", get(day19::list_db).post(day19::list_db)) // day19 task 5
         .with_state(state_pool)
         .nest_service("/assets", ServeDir::new("assets")) // day23 task 1